@@ -0,0 +1,393 @@
+use std::convert::Infallible;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tfhe::named::Named;
+use tfhe::prelude::*;
+use tfhe::{ClientKey, CompactCiphertextList, CompactPublicKey, FheBool, FheUint32};
+use tfhe_versionable::{Unversionize, Upgrade, Version, Versionize, VersionsDispatch};
+
+use crate::common::{
+    Order, Orders, safe_deserialize_item, safe_deserialize_item_with_limit, safe_serialize_item,
+    safe_serialize_item_with_limit,
+};
+use crate::config::ParameterProfile;
+use crate::error::DarkpoolError;
+use crate::keys::KeyFingerprint;
+use crate::limits::SerializationLimits;
+use crate::protocol::PayloadType;
+
+/// Number of ciphertexts packed per order: asset_a, asset_b, price and side.
+pub const FIELDS_PER_ORDER: usize = 4;
+
+/// The encrypted fields of a list of orders, one vector per field.
+///
+/// Index `i` of every vector belongs to the same order.
+#[derive(Serialize, Deserialize, Versionize)]
+#[versionize(EncryptedOrdersVersions)]
+pub struct EncryptedOrders {
+    pub asset_a: Vec<FheUint32>,
+    pub asset_b: Vec<FheUint32>,
+    pub price: Vec<FheUint32>,
+    pub side: Vec<FheBool>,
+    /// Whether each order lies within the bounds it was verified against, for
+    /// bounds its proof cannot cover. Orders outside them never match. `None`
+    /// if there was nothing left to check.
+    pub in_bounds: Option<Vec<FheBool>>,
+}
+
+/// Encrypted orders as first versioned, before bounds were checked on them.
+#[derive(Serialize, Deserialize, Version)]
+pub struct EncryptedOrdersV0 {
+    pub asset_a: Vec<FheUint32>,
+    pub asset_b: Vec<FheUint32>,
+    pub price: Vec<FheUint32>,
+    pub side: Vec<FheBool>,
+}
+
+impl Upgrade<EncryptedOrders> for EncryptedOrdersV0 {
+    type Error = Infallible;
+
+    fn upgrade(self) -> Result<EncryptedOrders, Self::Error> {
+        Ok(EncryptedOrders {
+            asset_a: self.asset_a,
+            asset_b: self.asset_b,
+            price: self.price,
+            side: self.side,
+            in_bounds: None,
+        })
+    }
+}
+
+#[derive(VersionsDispatch)]
+pub enum EncryptedOrdersVersions {
+    V0(EncryptedOrdersV0),
+    V1(EncryptedOrders),
+}
+
+impl Named for EncryptedOrders {
+    const NAME: &'static str = "fhe_darkpool::batch::EncryptedOrders";
+}
+
+impl EncryptedOrders {
+    /// Number of orders in the batch.
+    pub fn len(&self) -> usize {
+        self.price.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.price.is_empty()
+    }
+
+    /// Decrypts every order with the owner's client key.
+    ///
+    /// Order ids are never encrypted, so decrypted orders are numbered by position.
+    pub fn decrypt(&self, client_key: &ClientKey) -> Orders {
+        let order = (0..self.len())
+            .map(|i| Order {
+                id: i as u32,
+                asset_a: self.asset_a[i].decrypt(client_key),
+                asset_b: self.asset_b[i].decrypt(client_key),
+                price: self.price[i].decrypt(client_key),
+                a_for_b: self.side[i].decrypt(client_key),
+            })
+            .collect();
+        Orders { order }
+    }
+}
+
+/// Packs every field of every order into a single compact ciphertext list.
+///
+/// Fields are pushed order by order, in the layout described by `FIELDS_PER_ORDER`.
+pub fn encrypt_order_batch(
+    orders: &Orders,
+    public_key: &CompactPublicKey,
+) -> CompactCiphertextList {
+    let mut builder = CompactCiphertextList::builder(public_key);
+    for order in &orders.order {
+        builder
+            .push(order.asset_a)
+            .push(order.asset_b)
+            .push(order.price)
+            .push(order.a_for_b);
+    }
+    builder.build()
+}
+
+/// Expands a compact order batch back into per-field ciphertexts.
+///
+/// Expansion runs homomorphic operations, so the server key matching the
+/// public key used for encryption must be installed with `set_server_key`.
+pub fn expand_order_batch(list: &CompactCiphertextList) -> Result<EncryptedOrders, DarkpoolError> {
+    if !list.len().is_multiple_of(FIELDS_PER_ORDER) {
+        return Err(DarkpoolError::ProtocolViolation(format!(
+            "order batch holds {} ciphertexts, expected a multiple of {}",
+            list.len(),
+            FIELDS_PER_ORDER
+        )));
+    }
+
+    let count = list.len() / FIELDS_PER_ORDER;
+    let expander = list.expand()?;
+    let missing = |field: &str| DarkpoolError::ProtocolViolation(format!("missing {}", field));
+
+    let mut orders = EncryptedOrders {
+        asset_a: Vec::with_capacity(count),
+        asset_b: Vec::with_capacity(count),
+        price: Vec::with_capacity(count),
+        side: Vec::with_capacity(count),
+        in_bounds: None,
+    };
+    for i in 0..count {
+        let base = i * FIELDS_PER_ORDER;
+        orders.asset_a.push(
+            expander
+                .get::<FheUint32>(base)?
+                .ok_or_else(|| missing("asset_a"))?,
+        );
+        orders.asset_b.push(
+            expander
+                .get::<FheUint32>(base + 1)?
+                .ok_or_else(|| missing("asset_b"))?,
+        );
+        orders.price.push(
+            expander
+                .get::<FheUint32>(base + 2)?
+                .ok_or_else(|| missing("price"))?,
+        );
+        orders.side.push(
+            expander
+                .get::<FheBool>(base + 3)?
+                .ok_or_else(|| missing("side"))?,
+        );
+    }
+    Ok(orders)
+}
+
+/// Metadata carried by every serialized order and result batch.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(BatchHeaderVersions)]
+pub struct BatchHeader {
+    /// Parameter profile the keys were generated with.
+    pub profile: ParameterProfile,
+    /// Key epoch of the owner's key ring.
+    pub epoch: u64,
+    /// Fingerprint of the server key the ciphertexts were encrypted for.
+    pub key_fingerprint: KeyFingerprint,
+}
+
+impl BatchHeader {
+    /// Rejects a batch whose header does not match the locally installed key.
+    pub fn check(&self, expected: &BatchHeader) -> Result<(), DarkpoolError> {
+        if self.profile != expected.profile {
+            return Err(DarkpoolError::KeyMismatch(format!(
+                "batch uses parameter profile {:?}, but the installed key uses {:?}",
+                self.profile, expected.profile
+            )));
+        }
+        if self.epoch != expected.epoch {
+            return Err(DarkpoolError::KeyMismatch(format!(
+                "batch belongs to key epoch {}, but the installed key is epoch {}",
+                self.epoch, expected.epoch
+            )));
+        }
+        if self.key_fingerprint != expected.key_fingerprint {
+            return Err(DarkpoolError::KeyMismatch(format!(
+                "batch was encrypted for key {}, but the installed key is {}",
+                self.key_fingerprint, expected.key_fingerprint
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(VersionsDispatch)]
+pub enum BatchHeaderVersions {
+    V0(BatchHeader),
+}
+
+/// Wire format of an order upload: a header plus one serialized compact list.
+#[derive(Serialize, Deserialize, Versionize)]
+#[versionize(OrderBatchVersions)]
+pub struct OrderBatch {
+    pub header: BatchHeader,
+    pub ciphertexts: Vec<u8>,
+}
+
+#[derive(VersionsDispatch)]
+pub enum OrderBatchVersions {
+    V0(OrderBatch),
+}
+
+impl Named for OrderBatch {
+    const NAME: &'static str = "fhe_darkpool::batch::OrderBatch";
+}
+
+impl OrderBatch {
+    /// Encrypts a list of orders into one compact list stamped with `header`.
+    pub fn encrypt(
+        orders: &Orders,
+        public_key: &CompactPublicKey,
+        header: &BatchHeader,
+    ) -> Result<Self, DarkpoolError> {
+        Ok(Self {
+            header: *header,
+            ciphertexts: safe_serialize_item_with_limit(
+                &encrypt_order_batch(orders, public_key),
+                SerializationLimits::default().order_batch,
+            )?,
+        })
+    }
+
+    /// Checks the header against `expected`, then expands the ciphertexts.
+    ///
+    /// The ciphertexts are only bounded by their own length: the enclosing message
+    /// has already been checked against `SerializationLimits` by whoever decoded it.
+    pub fn expand(&self, expected: &BatchHeader) -> Result<EncryptedOrders, DarkpoolError> {
+        self.header.check(expected)?;
+        let list: CompactCiphertextList =
+            safe_deserialize_item_with_limit(&self.ciphertexts, self.ciphertexts.len() as u64)?;
+        expand_order_batch(&list)
+    }
+}
+
+/// Wire format of match results: a header plus one serialized `FheBool` per comparison.
+#[derive(Serialize, Deserialize, Versionize, Clone)]
+#[versionize(ResultBatchVersions)]
+pub struct ResultBatch {
+    pub header: BatchHeader,
+    pub results: Vec<Vec<u8>>,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ResultBatchVersions {
+    V0(ResultBatch),
+}
+
+impl Named for ResultBatch {
+    const NAME: &'static str = "fhe_darkpool::batch::ResultBatch";
+}
+
+impl ResultBatch {
+    pub fn new(results: &[FheBool], header: &BatchHeader) -> Result<Self, DarkpoolError> {
+        Ok(Self {
+            header: *header,
+            results: results
+                .iter()
+                .map(safe_serialize_item)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Wraps per-order match indices, as sent in `ResultMode::Index`.
+    pub fn from_indices(
+        indices: &[FheUint32],
+        header: &BatchHeader,
+    ) -> Result<Self, DarkpoolError> {
+        Ok(Self {
+            header: *header,
+            results: indices
+                .iter()
+                .map(safe_serialize_item)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Checks the header against `expected`, then deserializes every result.
+    pub fn results(&self, expected: &BatchHeader) -> Result<Vec<FheBool>, DarkpoolError> {
+        self.header.check(expected)?;
+        self.results
+            .iter()
+            .map(|bytes| safe_deserialize_item(bytes))
+            .collect()
+    }
+
+    /// Like `results`, for a batch built with `from_indices`.
+    pub fn indices(&self, expected: &BatchHeader) -> Result<Vec<FheUint32>, DarkpoolError> {
+        self.header.check(expected)?;
+        self.results
+            .iter()
+            .map(|bytes| safe_deserialize_item(bytes))
+            .collect()
+    }
+}
+
+/// Reads the header of a serialized order or result batch without expanding its ciphertexts.
+///
+/// This is how a receiver picks the key epoch to check the rest of the batch against.
+pub fn peek_batch_header(data: &[u8]) -> Result<BatchHeader, DarkpoolError> {
+    let limits = SerializationLimits::default();
+    read_batch::<OrderBatch>(data, limits.order_batch)
+        .map(|batch| batch.header)
+        .or_else(|e| {
+            read_batch::<ResultBatch>(data, limits.match_results)
+                .map(|batch| batch.header)
+                .map_err(|_| e)
+        })
+}
+
+/// Encrypts and serializes a list of orders as one upload, in the versioned
+/// format read by `deserialize_order_batch`.
+pub fn serialize_order_batch(
+    orders: &Orders,
+    public_key: &CompactPublicKey,
+    header: &BatchHeader,
+) -> Result<Vec<u8>, DarkpoolError> {
+    safe_serialize_item_with_limit(
+        &OrderBatch::encrypt(orders, public_key, header)?,
+        SerializationLimits::default().order_batch,
+    )
+}
+
+/// Deserializes an upload produced by `serialize_order_batch` and expands it.
+///
+/// Fails before touching the ciphertexts if the batch is over the default
+/// `SerializationLimits` or was made for another key.
+pub fn deserialize_order_batch(
+    data: &[u8],
+    expected: &BatchHeader,
+) -> Result<EncryptedOrders, DarkpoolError> {
+    SerializationLimits::default().check_payload(PayloadType::OrderBatch, data.len())?;
+    let batch: OrderBatch = read_batch(data, SerializationLimits::default().order_batch)?;
+    batch.expand(expected)
+}
+
+/// Serializes the encrypted results of a matching round.
+pub fn serialize_match_results(
+    results: &[FheBool],
+    header: &BatchHeader,
+) -> Result<Vec<u8>, DarkpoolError> {
+    safe_serialize_item_with_limit(
+        &ResultBatch::new(results, header)?,
+        SerializationLimits::default().match_results,
+    )
+}
+
+/// Deserializes match results, rejecting them if they were computed under another key.
+pub fn deserialize_match_results(
+    data: &[u8],
+    expected: &BatchHeader,
+) -> Result<Vec<FheBool>, DarkpoolError> {
+    SerializationLimits::default().check_payload(PayloadType::MatchResults, data.len())?;
+    let batch: ResultBatch = read_batch(data, SerializationLimits::default().match_results)?;
+    batch.results(expected)
+}
+
+/// Deserializes a versioned batch, upgrading it to the current layout.
+///
+/// Batches stored before they were versioned hold plain bincode; they are still
+/// accepted, as long as they decode completely.
+fn read_batch<T>(data: &[u8], limit: u64) -> Result<T, DarkpoolError>
+where
+    T: DeserializeOwned + Unversionize + Named,
+{
+    safe_deserialize_item_with_limit(data, limit).or_else(|e| {
+        bincode::options()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .with_limit(limit)
+            .deserialize(data)
+            .map_err(|_| e)
+    })
+}
@@ -0,0 +1,31 @@
+//! Pairs up traders and forwards their encrypted sessions.
+//!
+//! Usage: `darkpool-relay [LISTEN_ADDR]`, listening on 127.0.0.1:7420 by default.
+//! Traders connect with `MatchSession::connect_relay`.
+
+use fhe_darkpool_poc::relay::Relay;
+use tokio::sync::mpsc;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7420";
+/// Failures waiting to be logged; more are dropped until the log catches up.
+const FAILURE_BACKLOG: usize = 64;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let (failures, mut failed) = mpsc::channel(FAILURE_BACKLOG);
+    let relay = Relay::bind(&addr).await?.with_failures(failures);
+    println!("darkpool-relay listening on {}", relay.local_addr()?);
+    tokio::spawn(async move {
+        while let Some(failure) = failed.recv().await {
+            eprintln!(
+                "darkpool-relay: session initiated by {} failed: {}",
+                failure.initiator, failure.error
+            );
+        }
+    });
+    relay.run().await?;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! Serves the darkpool HTTP API, matching uploaded order batches against a book.
+//!
+//! Usage: `darkpool-service BOOK_FILE CRS_FILE [LISTEN_ADDR]`, listening on
+//! 127.0.0.1:7421 by default. The book holds plaintext orders, as written by
+//! `serialize_orders`, and the CRS file the CRS traders prove their orders
+//! against, as written by `safe_serialize_item`.
+
+use fhe_darkpool_poc::common::{deserialize_orders, safe_deserialize_item_with_limit};
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::service::Service;
+use tfhe::zk::CompactPkeCrs;
+use tokio::net::TcpListener;
+
+const USAGE: &str = "usage: darkpool-service BOOK_FILE CRS_FILE [LISTEN_ADDR]";
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7421";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let book_file = args.next().ok_or(USAGE)?;
+    let crs_file = args.next().ok_or(USAGE)?;
+    let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+
+    let book = deserialize_orders(&tokio::fs::read(&book_file).await?)?;
+    let crs: CompactPkeCrs = safe_deserialize_item_with_limit(
+        &tokio::fs::read(&crs_file).await?,
+        SerializationLimits::default().key_announcement,
+    )?;
+    let service = Service::new(Identity::generate(), book, crs);
+    let listener = TcpListener::bind(&addr).await?;
+    println!(
+        "darkpool-service {} listening on {}",
+        service.peer_id(),
+        listener.local_addr()?
+    );
+    service.serve(listener).await?;
+    Ok(())
+}
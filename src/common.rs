@@ -1,8 +1,13 @@
+use bincode::Options;
 use serde::{Deserialize, Serialize};
 use tfhe::named::Named;
-use tfhe::{Unversionize, Versionize};
+use tfhe_versionable::{Unversionize, Versionize, VersionsDispatch};
 
-#[derive(Serialize, Deserialize, Clone)]
+use crate::error::DarkpoolError;
+use crate::limits::{SerializationLimits, check_size};
+
+#[derive(Serialize, Deserialize, Versionize, Clone)]
+#[versionize(OrderVersions)]
 pub struct Order {
     pub id: u32,
     pub asset_a: u32,
@@ -11,27 +16,109 @@ pub struct Order {
     pub a_for_b: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(VersionsDispatch)]
+pub enum OrderVersions {
+    V0(Order),
+}
+
+#[derive(Serialize, Deserialize, Versionize, Clone)]
+#[versionize(OrdersVersions)]
 pub struct Orders {
     pub order: Vec<Order>,
 }
 
-pub fn safe_serialize_item<T>(item: &T) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+#[derive(VersionsDispatch)]
+pub enum OrdersVersions {
+    V0(Orders),
+}
+
+impl Named for Orders {
+    const NAME: &'static str = "fhe_darkpool::common::Orders";
+}
+
+/// Serializes plaintext orders in the versioned format read by `deserialize_orders`.
+pub fn serialize_orders(orders: &Orders) -> Result<Vec<u8>, DarkpoolError> {
+    safe_serialize_item_with_limit(orders, u64::MAX)
+}
+
+/// Deserializes plaintext orders, upgrading them to the current `Order` layout.
+///
+/// Order files written before orders were versioned hold plain bincode; they are
+/// still accepted, as long as they decode completely.
+pub fn deserialize_orders(data: &[u8]) -> Result<Orders, DarkpoolError> {
+    safe_deserialize_item_with_limit(data, data.len() as u64).or_else(|e| {
+        bincode::options()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(data)
+            .map_err(|_| e)
+    })
+}
+
+/// Serializes a single tfhe item, capped at `SerializationLimits::default().ciphertext`.
+pub fn safe_serialize_item<T>(item: &T) -> Result<Vec<u8>, DarkpoolError>
 where
     T: serde::Serialize + Versionize + Named,
 {
+    safe_serialize_item_with_limit(item, SerializationLimits::default().ciphertext)
+}
+
+/// Deserializes a single tfhe item, capped at `SerializationLimits::default().ciphertext`.
+pub fn safe_deserialize_item<T>(data: &[u8]) -> Result<T, DarkpoolError>
+where
+    T: serde::de::DeserializeOwned + Unversionize + Named,
+{
+    safe_deserialize_item_with_limit(data, SerializationLimits::default().ciphertext)
+}
+
+/// Serializes a tfhe item, failing with `SizeLimitExceeded` if it is larger than `limit`.
+pub fn safe_serialize_item_with_limit<T>(item: &T, limit: u64) -> Result<Vec<u8>, DarkpoolError>
+where
+    T: serde::Serialize + Versionize + Named,
+{
+    // Serialize without a cap first so the error can report the actual size.
     let mut buf = Vec::new();
-    // up to 1 MB
-    tfhe::safe_serialization::safe_serialize(item, &mut buf, 1 << 20)?;
+    tfhe::safe_serialization::safe_serialize(item, &mut buf, u64::MAX)
+        .map_err(DarkpoolError::Serialization)?;
+    check_size(T::NAME, buf.len(), limit)?;
     Ok(buf)
 }
 
-pub fn safe_deserialize_item<T>(data: &[u8]) -> Result<T, Box<dyn std::error::Error>>
+/// Deserializes a tfhe item, rejecting `data` with `SizeLimitExceeded` before
+/// decoding anything if it is larger than `limit`.
+pub fn safe_deserialize_item_with_limit<T>(data: &[u8], limit: u64) -> Result<T, DarkpoolError>
 where
     T: serde::de::DeserializeOwned + Unversionize + Named,
 {
     use std::io::Cursor;
+    check_size(T::NAME, data.len(), limit)?;
     let cursor = Cursor::new(data);
-    let item = tfhe::safe_serialization::safe_deserialize(cursor, 1 << 20)?;
+    let item = tfhe::safe_serialization::safe_deserialize(cursor, limit)
+        .map_err(DarkpoolError::ProtocolViolation)?;
     Ok(item)
 }
+
+/// Serializes a tfhe item straight into `writer`, without an intermediate buffer.
+///
+/// The size is only known once written, so going over `limit` fails with tfhe's
+/// own error rather than `SizeLimitExceeded`.
+pub fn safe_serialize_item_into<T, W>(item: &T, writer: W, limit: u64) -> Result<(), DarkpoolError>
+where
+    T: serde::Serialize + Versionize + Named,
+    W: std::io::Write,
+{
+    tfhe::safe_serialization::safe_serialize(item, writer, limit)
+        .map_err(DarkpoolError::Serialization)
+}
+
+/// Deserializes a tfhe item straight from `reader`, reading no more than `limit` bytes.
+///
+/// Only the bytes of this item are consumed, so several items can be read back to back.
+pub fn safe_deserialize_item_from<T, R>(reader: R, limit: u64) -> Result<T, DarkpoolError>
+where
+    T: serde::de::DeserializeOwned + Unversionize + Named,
+    R: std::io::Read,
+{
+    tfhe::safe_serialization::safe_deserialize(reader, limit)
+        .map_err(DarkpoolError::ProtocolViolation)
+}
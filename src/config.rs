@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use tfhe::shortint::parameters::{
+    PARAM_KEYSWITCH_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128,
+    PARAM_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64, PARAM_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128,
+    PARAM_PKE_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128, current_params,
+};
+use tfhe::{Config, ConfigBuilder};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::error::DarkpoolError;
+use crate::keys::KeySet;
+
+/// Named FHE parameter sets both parties must agree on before exchanging ciphertexts.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[versionize(ParameterProfileVersions)]
+pub enum ParameterProfile {
+    /// 2-bit message blocks, 128-bit security, failure probability below 2^-128.
+    #[default]
+    Default,
+    /// Failure probability below 2^-128, which `Default` now has as well; kept so
+    /// peers and key stores naming it still load.
+    LowFailureProbability,
+    /// 1-bit message blocks, failure probability below 2^-128: cheaper bootstraps,
+    /// suited to books of small values.
+    ///
+    /// There is no dedicated public key encryption set for these blocks, so order
+    /// batches are encrypted directly under the computation key.
+    SmallInteger,
+    /// Same block layout as `Default`, with a failure probability only below
+    /// 2^-64. Faster, but a bootstrap failure may leak or corrupt a result: for
+    /// tests and benchmarks, not for trading.
+    FastInsecure,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ParameterProfileVersions {
+    V0(ParameterProfile),
+}
+
+/// Crate-level configuration, turned into a tfhe `Config` for key generation.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[versionize(DarkpoolConfigVersions)]
+pub struct DarkpoolConfig {
+    pub profile: ParameterProfile,
+}
+
+#[derive(VersionsDispatch)]
+pub enum DarkpoolConfigVersions {
+    V0(DarkpoolConfig),
+}
+
+impl DarkpoolConfig {
+    pub fn new(profile: ParameterProfile) -> Self {
+        Self { profile }
+    }
+
+    /// Builds the tfhe config for this profile.
+    ///
+    /// The 2-bit profiles enable dedicated compact public key parameters so that
+    /// order batches can be encrypted with a `CompactPublicKey`.
+    pub fn tfhe_config(&self) -> Config {
+        let dedicated_pke = (
+            PARAM_PKE_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128,
+            PARAM_KEYSWITCH_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128,
+        );
+        match self.profile {
+            ParameterProfile::Default | ParameterProfile::LowFailureProbability => {
+                ConfigBuilder::with_custom_parameters(PARAM_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M128)
+                    .use_dedicated_compact_public_key_parameters(dedicated_pke)
+                    .build()
+            }
+            ParameterProfile::SmallInteger => ConfigBuilder::with_custom_parameters(
+                current_params::V1_1_PARAM_MESSAGE_1_CARRY_1_KS_PBS_TUNIFORM_2M128,
+            )
+            .build(),
+            ParameterProfile::FastInsecure => {
+                ConfigBuilder::with_custom_parameters(PARAM_MESSAGE_2_CARRY_2_KS_PBS_TUNIFORM_2M64)
+                    .use_dedicated_compact_public_key_parameters(dedicated_pke)
+                    .build()
+            }
+        }
+    }
+
+    /// Generates a fresh key set for this configuration.
+    pub fn generate_keys(&self) -> Result<KeySet, DarkpoolError> {
+        KeySet::generate(*self)
+    }
+}
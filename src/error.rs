@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io;
+
+use crate::limits::SizeLimitExceeded;
+
+/// Every way a darkpool operation can fail.
+///
+/// Variants are grouped by who is at fault, so callers can react accordingly:
+/// `Transport` errors are worth retrying, `ProtocolViolation`, `SizeLimitExceeded`
+/// and `InvalidOrder` point at a misbehaving peer, `VersionMismatch` and
+/// `KeyMismatch` at peers that need to resynchronise before talking again, and
+/// `HandshakeMismatch` at peers that cannot trade with each other as configured.
+#[derive(Debug)]
+pub enum DarkpoolError {
+    /// Serialized data is larger than the limit for its type.
+    SizeLimitExceeded(SizeLimitExceeded),
+    /// The peer speaks another protocol version.
+    VersionMismatch { peer: u16, local: u16 },
+    /// Data was made for other keys, parameters or key epochs than the local ones.
+    KeyMismatch(String),
+    /// Peers could not agree on a session setting during the handshake.
+    HandshakeMismatch {
+        setting: &'static str,
+        local: String,
+        peer: String,
+    },
+    /// An order does not fit the field bounds it is checked against.
+    InvalidOrder(String),
+    /// Reading from or writing to a peer failed.
+    Transport(io::Error),
+    /// A peer sent something malformed: undecodable bytes, a mislabelled payload
+    /// or a batch whose proofs do not verify.
+    ProtocolViolation(String),
+    /// A sealed client key was opened with the wrong passphrase, or is corrupted.
+    WrongPassphrase,
+    /// Stored or backed-up keys are missing, inconsistent or cannot be processed.
+    KeyStore(String),
+    /// A local file operation failed.
+    Io(io::Error),
+    /// Local data could not be serialized, e.g. because it outgrew its limit.
+    Serialization(bincode::Error),
+    /// tfhe itself failed, e.g. while generating keys or expanding ciphertexts.
+    Fhe(tfhe::Error),
+}
+
+impl DarkpoolError {
+    /// Whether the same operation may succeed if tried again, over a new connection.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, DarkpoolError::Transport(_))
+    }
+}
+
+impl fmt::Display for DarkpoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DarkpoolError::SizeLimitExceeded(e) => write!(f, "{}", e),
+            DarkpoolError::VersionMismatch { peer, local } => write!(
+                f,
+                "peer speaks protocol version {}, this build speaks version {}",
+                peer, local
+            ),
+            DarkpoolError::KeyMismatch(message) => write!(f, "key mismatch: {}", message),
+            DarkpoolError::HandshakeMismatch {
+                setting,
+                local,
+                peer,
+            } => write!(
+                f,
+                "cannot agree on {}: this party offers {}, the peer offers {}",
+                setting, local, peer
+            ),
+            DarkpoolError::InvalidOrder(message) => write!(f, "invalid order: {}", message),
+            DarkpoolError::Transport(e) => write!(f, "transport failure: {}", e),
+            DarkpoolError::ProtocolViolation(message) => {
+                write!(f, "protocol violation: {}", message)
+            }
+            DarkpoolError::WrongPassphrase => {
+                write!(f, "wrong passphrase, or the sealed client key is corrupted")
+            }
+            DarkpoolError::KeyStore(message) => write!(f, "key store: {}", message),
+            DarkpoolError::Io(e) => write!(f, "{}", e),
+            DarkpoolError::Serialization(e) => write!(f, "cannot serialize: {}", e),
+            DarkpoolError::Fhe(e) => write!(f, "tfhe: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DarkpoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DarkpoolError::SizeLimitExceeded(e) => Some(e),
+            DarkpoolError::Transport(e) | DarkpoolError::Io(e) => Some(e),
+            DarkpoolError::Serialization(e) => Some(e),
+            DarkpoolError::Fhe(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SizeLimitExceeded> for DarkpoolError {
+    fn from(e: SizeLimitExceeded) -> Self {
+        DarkpoolError::SizeLimitExceeded(e)
+    }
+}
+
+impl From<io::Error> for DarkpoolError {
+    fn from(e: io::Error) -> Self {
+        DarkpoolError::Io(e)
+    }
+}
+
+impl From<tfhe::Error> for DarkpoolError {
+    fn from(e: tfhe::Error) -> Self {
+        DarkpoolError::Fhe(e)
+    }
+}
+
+impl From<serde_json::Error> for DarkpoolError {
+    fn from(e: serde_json::Error) -> Self {
+        DarkpoolError::ProtocolViolation(e.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for DarkpoolError {
+    fn from(e: base64::DecodeError) -> Self {
+        DarkpoolError::ProtocolViolation(e.to_string())
+    }
+}
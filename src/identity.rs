@@ -0,0 +1,116 @@
+use std::fmt;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tfhe::named::Named;
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::error::DarkpoolError;
+
+/// Prefix of every signed statement, so envelope signatures cannot be replayed
+/// as signatures over anything else.
+const SIGNING_CONTEXT: &[u8] = b"fhe-darkpool envelope v1";
+
+/// Public Ed25519 key identifying a party.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[versionize(PeerIdVersions)]
+pub struct PeerId(pub [u8; 32]);
+
+#[derive(VersionsDispatch)]
+pub enum PeerIdVersions {
+    V0(PeerId),
+}
+
+impl PeerId {
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0[..8] {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A party's long-term signing key.
+///
+/// Unlike FHE keys, identities are not rotated with key epochs: they are how
+/// counterparties recognise each other across rotations.
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn from_secret_bytes(secret: &[u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId(self.signing_key.verifying_key().to_bytes())
+    }
+
+    /// Signs an envelope given its serialized header and the SHA-256 digest of its body.
+    pub fn sign_envelope(&self, header: &[u8], payload_digest: &[u8; 32]) -> EnvelopeSignature {
+        let signature = self
+            .signing_key
+            .sign(&signed_statement(header, payload_digest));
+        EnvelopeSignature {
+            signer: self.peer_id(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+}
+
+/// Trailer of every envelope: who signed it, and the signature itself.
+#[derive(Serialize, Deserialize, Versionize, Clone, PartialEq, Eq, Debug)]
+#[versionize(EnvelopeSignatureVersions)]
+pub struct EnvelopeSignature {
+    pub signer: PeerId,
+    pub signature: Vec<u8>,
+}
+
+#[derive(VersionsDispatch)]
+pub enum EnvelopeSignatureVersions {
+    V0(EnvelopeSignature),
+}
+
+impl Named for EnvelopeSignature {
+    const NAME: &'static str = "fhe_darkpool::identity::EnvelopeSignature";
+}
+
+impl EnvelopeSignature {
+    /// Checks the signature over a serialized header and the digest of its body.
+    ///
+    /// Kept alongside the header and digest, a verified signature is proof of
+    /// which party sent a given batch or result set.
+    pub fn verify(&self, header: &[u8], payload_digest: &[u8; 32]) -> Result<(), DarkpoolError> {
+        let invalid =
+            || DarkpoolError::ProtocolViolation(format!("invalid signature from {}", self.signer));
+        let key = VerifyingKey::from_bytes(&self.signer.0).map_err(|_| invalid())?;
+        let signature = Signature::from_slice(&self.signature).map_err(|_| invalid())?;
+        key.verify_strict(&signed_statement(header, payload_digest), &signature)
+            .map_err(|_| invalid())
+    }
+}
+
+fn signed_statement(header: &[u8], payload_digest: &[u8; 32]) -> Vec<u8> {
+    [SIGNING_CONTEXT, header, payload_digest].concat()
+}
@@ -0,0 +1,446 @@
+pub mod at_rest;
+pub mod backup;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tfhe::prelude::*;
+use tfhe::{ClientKey, CompactPublicKey, ServerKey, with_server_key_as_context};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::batch::{
+    BatchHeader, deserialize_match_results, deserialize_order_batch, peek_batch_header,
+    serialize_order_batch,
+};
+use crate::config::DarkpoolConfig;
+use crate::error::DarkpoolError;
+use crate::limits::SerializationLimits;
+use at_rest::SealedClientKey;
+
+/// SHA-256 digest identifying the server key a ciphertext batch belongs to.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[versionize(KeyFingerprintVersions)]
+pub struct KeyFingerprint(pub [u8; 32]);
+
+#[derive(VersionsDispatch)]
+pub enum KeyFingerprintVersions {
+    V0(KeyFingerprint),
+}
+
+impl KeyFingerprint {
+    /// Hashes the bincode encoding of a server key.
+    ///
+    /// The key is streamed into the hasher, so no second copy of it is materialised.
+    pub fn of_server_key(server_key: &ServerKey) -> Result<Self, DarkpoolError> {
+        let mut hasher = Sha256::new();
+        bincode::serialize_into(&mut hasher, server_key).map_err(DarkpoolError::Serialization)?;
+        Ok(Self(hasher.finalize().into()))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The first 8 bytes are plenty to tell keys apart in logs.
+        for b in &self.0[..8] {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything a desk needs to encrypt orders and let counterparties evaluate them.
+pub struct KeySet {
+    pub config: DarkpoolConfig,
+    /// Position of this key pair in its owner's rotation history.
+    pub epoch: u64,
+    pub client_key: ClientKey,
+    pub server_key: ServerKey,
+    pub public_key: CompactPublicKey,
+    pub fingerprint: KeyFingerprint,
+}
+
+impl KeySet {
+    /// Generates client, server and compact public keys for `config`, at epoch 0.
+    pub fn generate(config: DarkpoolConfig) -> Result<Self, DarkpoolError> {
+        Self::generate_for_epoch(config, 0)
+    }
+
+    /// Generates client, server and compact public keys for `config` and `epoch`.
+    pub fn generate_for_epoch(config: DarkpoolConfig, epoch: u64) -> Result<Self, DarkpoolError> {
+        let client_key = ClientKey::generate(config.tfhe_config());
+        let server_key = ServerKey::new(&client_key);
+        let public_key = CompactPublicKey::try_new(&client_key)?;
+        let fingerprint = KeyFingerprint::of_server_key(&server_key)?;
+        Ok(Self {
+            config,
+            epoch,
+            client_key,
+            server_key,
+            public_key,
+            fingerprint,
+        })
+    }
+
+    /// Header stamped on every batch encrypted under, or evaluated with, these keys.
+    pub fn batch_header(&self) -> BatchHeader {
+        BatchHeader {
+            profile: self.config.profile,
+            epoch: self.epoch,
+            key_fingerprint: self.fingerprint,
+        }
+    }
+}
+
+/// A key set that has been rotated out, kept around to decrypt outstanding results.
+pub struct RetiredKeys {
+    pub keys: KeySet,
+    pub retired_at: SystemTime,
+}
+
+impl RetiredKeys {
+    fn is_expired(&self, grace_period: Duration) -> bool {
+        self.retired_at
+            .elapsed()
+            .is_ok_and(|age| age > grace_period)
+    }
+}
+
+/// A desk's current key set plus the retired epochs still inside their grace period.
+pub struct KeyRing {
+    current: KeySet,
+    retired: Vec<RetiredKeys>,
+    grace_period: Duration,
+}
+
+impl KeyRing {
+    /// How long retired keys stay usable unless configured otherwise.
+    pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new(current: KeySet, grace_period: Duration) -> Self {
+        Self {
+            current,
+            retired: Vec::new(),
+            grace_period,
+        }
+    }
+
+    /// Keys new orders are encrypted under.
+    pub fn current(&self) -> &KeySet {
+        &self.current
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.current.epoch
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn retired(&self) -> &[RetiredKeys] {
+        &self.retired
+    }
+
+    /// Generates keys for the next epoch and retires the current ones.
+    ///
+    /// Retired keys can still decrypt results until their grace period runs out.
+    pub fn rotate(&mut self) -> Result<&KeySet, DarkpoolError> {
+        let next = KeySet::generate_for_epoch(self.current.config, self.current.epoch + 1)?;
+        let previous = std::mem::replace(&mut self.current, next);
+        self.retired.push(RetiredKeys {
+            keys: previous,
+            retired_at: SystemTime::now(),
+        });
+        Ok(&self.current)
+    }
+
+    /// Returns the keys for `epoch` if it is current or still within its grace period.
+    pub fn keys_for_epoch(&self, epoch: u64) -> Option<&KeySet> {
+        if epoch == self.current.epoch {
+            return Some(&self.current);
+        }
+        self.retired
+            .iter()
+            .find(|r| r.keys.epoch == epoch && !r.is_expired(self.grace_period))
+            .map(|r| &r.keys)
+    }
+
+    /// Drops retired keys whose grace period has ended, returning how many were dropped.
+    pub fn prune_expired(&mut self) -> usize {
+        let before = self.retired.len();
+        let grace_period = self.grace_period;
+        self.retired.retain(|r| !r.is_expired(grace_period));
+        before - self.retired.len()
+    }
+
+    fn keys_for_header(&self, header: &BatchHeader) -> Result<&KeySet, DarkpoolError> {
+        let keys = self.keys_for_epoch(header.epoch).ok_or_else(|| {
+            DarkpoolError::KeyMismatch(format!(
+                "key epoch {} is unknown or past its grace period (current epoch is {})",
+                header.epoch, self.current.epoch
+            ))
+        })?;
+        header.check(&keys.batch_header())?;
+        Ok(keys)
+    }
+
+    /// Decrypts match results computed under any epoch that is still usable.
+    pub fn decrypt_match_results(&self, data: &[u8]) -> Result<Vec<bool>, DarkpoolError> {
+        let keys = self.keys_for_header(&peek_batch_header(data)?)?;
+        let results = deserialize_match_results(data, &keys.batch_header())?;
+        Ok(results
+            .iter()
+            .map(|r| r.decrypt(&keys.client_key))
+            .collect())
+    }
+
+    /// Re-encrypts resting orders from an older epoch under the current keys.
+    ///
+    /// The batch is expanded with its own epoch's server key, decrypted with the
+    /// matching client key and encrypted again for the current epoch.
+    pub fn reencrypt_resting_orders(&self, data: &[u8]) -> Result<Vec<u8>, DarkpoolError> {
+        let old = self.keys_for_header(&peek_batch_header(data)?)?;
+        let expanded = with_server_key_as_context(old.server_key.clone(), || {
+            deserialize_order_batch(data, &old.batch_header())
+        })?;
+        let orders = expanded.decrypt(&old.client_key);
+        serialize_order_batch(
+            &orders,
+            &self.current.public_key,
+            &self.current.batch_header(),
+        )
+    }
+}
+
+/// Per-epoch entry of the key store manifest.
+#[derive(Serialize, Deserialize)]
+struct EpochRecord {
+    epoch: u64,
+    fingerprint: KeyFingerprint,
+    retired_at: Option<SystemTime>,
+}
+
+/// Contents of `manifest.bin`, written next to the per-epoch key directories.
+#[derive(Serialize, Deserialize)]
+struct KeyStoreManifest {
+    config: DarkpoolConfig,
+    grace_period: Duration,
+    /// Whether client keys are stored as passphrase-sealed blobs.
+    sealed_client_keys: bool,
+    epochs: Vec<EpochRecord>,
+}
+
+/// A directory holding one desk's key ring and the configuration it was generated with.
+///
+/// Each epoch lives in its own `epoch-<n>` subdirectory. When opened with a
+/// passphrase, client keys are sealed with `SealedClientKey` instead of being
+/// written in plaintext.
+pub struct KeyStore {
+    root: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl KeyStore {
+    const MANIFEST: &'static str = "manifest.bin";
+    const CLIENT_KEY: &'static str = "client_key.bin";
+    const SEALED_CLIENT_KEY: &'static str = "client_key.sealed";
+    const SERVER_KEY: &'static str = "server_key.bin";
+    const PUBLIC_KEY: &'static str = "public_key.bin";
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            passphrase: None,
+        }
+    }
+
+    /// Seals client keys on save, and unseals them on load, with `passphrase`.
+    pub fn with_passphrase(mut self, passphrase: impl Into<String>) -> Self {
+        self.passphrase = Some(passphrase.into());
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn epoch_dir(&self, epoch: u64) -> PathBuf {
+        self.root.join(format!("epoch-{}", epoch))
+    }
+
+    /// Writes every epoch of the ring, creating directories as needed.
+    ///
+    /// Epoch directories no longer referenced by the ring are removed.
+    pub fn save(&self, ring: &KeyRing) -> Result<(), DarkpoolError> {
+        let mut epochs = Vec::with_capacity(ring.retired.len() + 1);
+        for retired in &ring.retired {
+            self.save_epoch(&retired.keys)?;
+            epochs.push(EpochRecord {
+                epoch: retired.keys.epoch,
+                fingerprint: retired.keys.fingerprint,
+                retired_at: Some(retired.retired_at),
+            });
+        }
+        self.save_epoch(&ring.current)?;
+        epochs.push(EpochRecord {
+            epoch: ring.current.epoch,
+            fingerprint: ring.current.fingerprint,
+            retired_at: None,
+        });
+
+        let manifest = KeyStoreManifest {
+            config: ring.current.config,
+            grace_period: ring.grace_period,
+            sealed_client_keys: self.passphrase.is_some(),
+            epochs,
+        };
+        write_file(&self.root.join(Self::MANIFEST), &manifest)?;
+
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            let stale = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("epoch-"))
+                .and_then(|epoch| epoch.parse::<u64>().ok())
+                .is_some_and(|epoch| !manifest.epochs.iter().any(|e| e.epoch == epoch));
+            if stale {
+                fs::remove_dir_all(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_epoch(&self, keys: &KeySet) -> Result<(), DarkpoolError> {
+        let dir = self.epoch_dir(keys.epoch);
+        fs::create_dir_all(&dir)?;
+        match &self.passphrase {
+            Some(passphrase) => {
+                // Sealing needs the whole plaintext at once, so this is the one key
+                // that goes through memory.
+                let client_key = bincode::serialize(&keys.client_key)
+                    .map_err(|e| DarkpoolError::KeyStore(e.to_string()))?;
+                let sealed = SealedClientKey::seal(&client_key, passphrase)?;
+                write_file(&dir.join(Self::SEALED_CLIENT_KEY), &sealed)?;
+                // Never leave a plaintext copy behind from an earlier unsealed save.
+                let plaintext = dir.join(Self::CLIENT_KEY);
+                if plaintext.exists() {
+                    fs::remove_file(plaintext)?;
+                }
+            }
+            None => write_file(&dir.join(Self::CLIENT_KEY), &keys.client_key)?,
+        }
+        write_file(&dir.join(Self::SERVER_KEY), &keys.server_key)?;
+        write_file(&dir.join(Self::PUBLIC_KEY), &keys.public_key)?;
+        Ok(())
+    }
+
+    /// Reads the configuration recorded with the stored keys, without loading them.
+    pub fn load_config(&self) -> Result<DarkpoolConfig, DarkpoolError> {
+        Ok(self.load_manifest()?.config)
+    }
+
+    /// Loads the key ring, checking every server key against the manifest.
+    pub fn load(&self) -> Result<KeyRing, DarkpoolError> {
+        let manifest = self.load_manifest()?;
+        let mut current = None;
+        let mut retired = Vec::new();
+        for record in &manifest.epochs {
+            let keys = self.load_epoch(&manifest, record)?;
+            match record.retired_at {
+                Some(retired_at) => retired.push(RetiredKeys { keys, retired_at }),
+                None => current = Some(keys),
+            }
+        }
+        let current = current.ok_or_else(|| {
+            DarkpoolError::KeyStore(format!("{} has no current epoch", self.root.display()))
+        })?;
+        Ok(KeyRing {
+            current,
+            retired,
+            grace_period: manifest.grace_period,
+        })
+    }
+
+    fn load_epoch(
+        &self,
+        manifest: &KeyStoreManifest,
+        record: &EpochRecord,
+    ) -> Result<KeySet, DarkpoolError> {
+        let dir = self.epoch_dir(record.epoch);
+        let client_key: ClientKey = if manifest.sealed_client_keys {
+            let passphrase = self.passphrase.as_deref().ok_or_else(|| {
+                DarkpoolError::KeyStore(format!(
+                    "client keys in {} are sealed, open the store with a passphrase",
+                    self.root.display()
+                ))
+            })?;
+            let sealed: SealedClientKey = read_file(&dir.join(Self::SEALED_CLIENT_KEY))?;
+            bincode::deserialize(&sealed.open(passphrase)?)
+                .map_err(|e| DarkpoolError::KeyStore(e.to_string()))?
+        } else {
+            read_file(&dir.join(Self::CLIENT_KEY))?
+        };
+        let server_key: ServerKey = read_file(&dir.join(Self::SERVER_KEY))?;
+        let public_key: CompactPublicKey = read_file(&dir.join(Self::PUBLIC_KEY))?;
+
+        let fingerprint = KeyFingerprint::of_server_key(&server_key)?;
+        if fingerprint != record.fingerprint {
+            return Err(DarkpoolError::KeyStore(format!(
+                "{} holds server key {}, but the manifest records {}",
+                dir.display(),
+                fingerprint,
+                record.fingerprint
+            )));
+        }
+
+        Ok(KeySet {
+            config: manifest.config,
+            epoch: record.epoch,
+            client_key,
+            server_key,
+            public_key,
+            fingerprint,
+        })
+    }
+
+    fn load_manifest(&self) -> Result<KeyStoreManifest, DarkpoolError> {
+        read_file(&self.root.join(Self::MANIFEST))
+    }
+}
+
+/// Serializes `value` straight into a file, without an intermediate buffer.
+fn write_file<T: Serialize>(path: &Path, value: &T) -> Result<(), DarkpoolError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    bincode::serialize_into(&mut writer, value).map_err(|e| store_error(path, e))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Deserializes a value straight from a file, without reading it into memory first.
+///
+/// Nothing in the store is larger than a server key, so decoding stops at the
+/// key announcement limit rather than trusting lengths read from the file.
+fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, DarkpoolError> {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(SerializationLimits::default().key_announcement)
+        .deserialize_from(BufReader::new(File::open(path)?))
+        .map_err(|e| store_error(path, e))
+}
+
+fn store_error(path: &Path, e: bincode::Error) -> DarkpoolError {
+    DarkpoolError::KeyStore(format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,80 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::random;
+use serde::{Deserialize, Serialize};
+
+use crate::error::DarkpoolError;
+
+/// Associated data binding sealed blobs to their purpose.
+const AAD: &[u8] = b"fhe-darkpool sealed client key v1";
+
+/// A serialized client key encrypted with a passphrase-derived key.
+///
+/// The key is derived with Argon2id and the blob sealed with ChaCha20-Poly1305.
+/// KDF costs are stored alongside so they can be raised without breaking old files.
+#[derive(Serialize, Deserialize)]
+pub struct SealedClientKey {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+impl SealedClientKey {
+    /// Encrypts `plaintext` under `passphrase` with a fresh salt and nonce.
+    pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Self, DarkpoolError> {
+        let params = Params::default();
+        let mut sealed = SealedClientKey {
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            salt: random(),
+            nonce: random(),
+            ciphertext: Vec::new(),
+        };
+        let cipher = sealed.cipher(passphrase)?;
+        sealed.ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&sealed.nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: AAD,
+                },
+            )
+            .map_err(|e| DarkpoolError::KeyStore(format!("sealing client key failed: {}", e)))?;
+        Ok(sealed)
+    }
+
+    /// Decrypts the serialized client key, failing with `DarkpoolError::WrongPassphrase`
+    /// on mismatch.
+    ///
+    /// AEAD decryption cannot tell a wrong passphrase from a tampered file, so both
+    /// end up there.
+    pub fn open(&self, passphrase: &str) -> Result<Vec<u8>, DarkpoolError> {
+        let cipher = self.cipher(passphrase)?;
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &self.ciphertext,
+                    aad: AAD,
+                },
+            )
+            .map_err(|_| DarkpoolError::WrongPassphrase)?;
+        Ok(plaintext)
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305, DarkpoolError> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32)).map_err(|e| {
+            DarkpoolError::KeyStore(format!("invalid key derivation parameters: {}", e))
+        })?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| DarkpoolError::KeyStore(format!("key derivation failed: {}", e)))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use rand::{RngCore, thread_rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tfhe::ClientKey;
+
+use crate::error::DarkpoolError;
+
+/// One share of a k-of-n Shamir split of a serialized client key.
+///
+/// Every share carries the SHA-256 digest of the whole secret, so a reconstruction
+/// from wrong or tampered shares is detected instead of yielding a garbage key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyShare {
+    /// Evaluation point of this share, in `1..=n`.
+    pub index: u8,
+    /// Number of shares needed to reconstruct the secret.
+    pub threshold: u8,
+    pub digest: [u8; 32],
+    pub data: Vec<u8>,
+}
+
+/// Splits a client key into `count` shares, any `threshold` of which rebuild it.
+pub fn split_client_key(
+    client_key: &ClientKey,
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<KeyShare>, DarkpoolError> {
+    let secret = bincode::serialize(client_key).map_err(DarkpoolError::Serialization)?;
+    split_secret(&secret, threshold, count)
+}
+
+/// Rebuilds a client key from at least `threshold` of its shares.
+pub fn reconstruct_client_key(shares: &[KeyShare]) -> Result<ClientKey, DarkpoolError> {
+    bincode::deserialize(&combine_shares(shares)?)
+        .map_err(|e| DarkpoolError::KeyStore(e.to_string()))
+}
+
+/// Splits arbitrary bytes with Shamir's scheme over GF(2^8), byte by byte.
+pub fn split_secret(
+    secret: &[u8],
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<KeyShare>, DarkpoolError> {
+    if threshold == 0 || threshold > count {
+        return Err(DarkpoolError::KeyStore(format!(
+            "cannot split into {} shares with a threshold of {}",
+            count, threshold
+        )));
+    }
+
+    let digest: [u8; 32] = Sha256::digest(secret).into();
+    let mut shares: Vec<KeyShare> = (1..=count)
+        .map(|index| KeyShare {
+            index,
+            threshold,
+            digest,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    // coefficients[0] is the secret byte, the rest are fresh random bytes.
+    let mut rng = thread_rng();
+    let mut coefficients = vec![0u8; threshold as usize];
+    for &byte in secret {
+        coefficients[0] = byte;
+        rng.fill_bytes(&mut coefficients[1..]);
+        for share in &mut shares {
+            share.data.push(evaluate(&coefficients, share.index));
+        }
+    }
+    Ok(shares)
+}
+
+/// Interpolates the secret from shares and checks it against their digest.
+pub fn combine_shares(shares: &[KeyShare]) -> Result<Vec<u8>, DarkpoolError> {
+    let first = shares
+        .first()
+        .ok_or_else(|| DarkpoolError::KeyStore("no key shares given".to_string()))?;
+    let threshold = first.threshold as usize;
+    if shares.len() < threshold {
+        return Err(DarkpoolError::KeyStore(format!(
+            "{} key shares given, but {} are needed",
+            shares.len(),
+            threshold
+        )));
+    }
+
+    let shares = &shares[..threshold];
+    let mut seen = HashSet::new();
+    for share in shares {
+        if share.threshold != first.threshold
+            || share.digest != first.digest
+            || share.data.len() != first.data.len()
+        {
+            return Err(DarkpoolError::KeyStore(
+                "key shares belong to different secrets".to_string(),
+            ));
+        }
+        if share.index == 0 || !seen.insert(share.index) {
+            return Err(DarkpoolError::KeyStore(format!(
+                "invalid or duplicate key share index {}",
+                share.index
+            )));
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at x = 0; subtraction in GF(2^8) is xor.
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            shares
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold(1u8, |acc, other| {
+                    gf_mul(acc, gf_div(other.index, other.index ^ share.index))
+                })
+        })
+        .collect();
+
+    let secret: Vec<u8> = (0..first.data.len())
+        .map(|i| {
+            shares
+                .iter()
+                .zip(&basis)
+                .fold(0u8, |acc, (share, &l)| acc ^ gf_mul(share.data[i], l))
+        })
+        .collect();
+
+    let digest: [u8; 32] = Sha256::digest(&secret).into();
+    if digest != first.digest {
+        return Err(DarkpoolError::KeyStore(
+            "reconstructed secret does not match its digest, shares are wrong or tampered"
+                .to_string(),
+        ));
+    }
+    Ok(secret)
+}
+
+/// Evaluates the polynomial with the given coefficients at `x` (Horner's rule).
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0u8, |acc, &c| gf_mul(acc, x) ^ c)
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Division in GF(2^8); `b` must be non-zero. Uses b^254 = b^-1.
+fn gf_div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1u8;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
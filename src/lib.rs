@@ -1,2 +1,15 @@
+pub mod batch;
 pub mod common;
+pub mod config;
+pub mod error;
+pub mod identity;
+pub mod keys;
+pub mod limits;
+pub mod matching;
+pub mod proofs;
+pub mod protocol;
+pub mod relay;
+pub mod service;
+pub mod session;
 pub mod test_data;
+pub mod transport;
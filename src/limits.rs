@@ -0,0 +1,134 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::protocol::PayloadType;
+
+/// Size caps applied before decoding anything received from a peer.
+///
+/// Every cap is in bytes of serialized data. Defaults are sized for the
+/// default parameter profile; evaluators exposed to untrusted peers can
+/// lower them to what their order book actually needs.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(SerializationLimitsVersions)]
+pub struct SerializationLimits {
+    /// A protocol header.
+    pub header: u64,
+    /// A single serialized ciphertext, e.g. one `FheUint32` or `FheBool`.
+    pub ciphertext: u64,
+    /// A key announcement, dominated by the server key.
+    pub key_announcement: u64,
+    /// An order batch without proofs.
+    pub order_batch: u64,
+    /// An order batch with its zero-knowledge proofs.
+    pub proven_order_batch: u64,
+    /// The encrypted results of one matching round.
+    pub match_results: u64,
+    /// An error message.
+    pub error: u64,
+    /// A handshake message.
+    pub handshake: u64,
+}
+
+#[derive(VersionsDispatch)]
+pub enum SerializationLimitsVersions {
+    V0(SerializationLimits),
+}
+
+impl Default for SerializationLimits {
+    fn default() -> Self {
+        Self {
+            header: 1 << 10,
+            ciphertext: 1 << 20,
+            key_announcement: 1 << 30,
+            order_batch: 1 << 26,
+            proven_order_batch: 1 << 28,
+            match_results: 1 << 28,
+            error: 1 << 16,
+            handshake: 1 << 12,
+        }
+    }
+}
+
+impl SerializationLimits {
+    /// Cap for the body of a message of the given type.
+    pub fn for_payload(&self, payload_type: PayloadType) -> u64 {
+        match payload_type {
+            PayloadType::KeyAnnouncement => self.key_announcement,
+            PayloadType::OrderBatch => self.order_batch,
+            PayloadType::ProvenOrderBatch => self.proven_order_batch,
+            PayloadType::MatchResults => self.match_results,
+            PayloadType::Abort => self.error,
+            PayloadType::Hello => self.handshake,
+        }
+    }
+
+    /// The tighter of each pair of caps, so that data within the result is
+    /// accepted by both parties.
+    pub fn intersect(&self, other: &Self) -> Self {
+        Self {
+            header: self.header.min(other.header),
+            ciphertext: self.ciphertext.min(other.ciphertext),
+            key_announcement: self.key_announcement.min(other.key_announcement),
+            order_batch: self.order_batch.min(other.order_batch),
+            proven_order_batch: self.proven_order_batch.min(other.proven_order_batch),
+            match_results: self.match_results.min(other.match_results),
+            error: self.error.min(other.error),
+            handshake: self.handshake.min(other.handshake),
+        }
+    }
+
+    /// Rejects a message body of `size` bytes if it exceeds the cap for `payload_type`.
+    pub fn check_payload(
+        &self,
+        payload_type: PayloadType,
+        size: usize,
+    ) -> Result<(), SizeLimitExceeded> {
+        check_size(
+            format!("{:?}", payload_type),
+            size,
+            self.for_payload(payload_type),
+        )
+    }
+}
+
+/// Returned when serialized data is larger than the limit allowed for its type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SizeLimitExceeded {
+    /// Type of the offending item or message.
+    pub what: String,
+    /// Its serialized size in bytes.
+    pub size: u64,
+    /// The limit it was checked against.
+    pub limit: u64,
+}
+
+impl fmt::Display for SizeLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "serialized {} is {} bytes, over the limit of {} bytes",
+            self.what, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for SizeLimitExceeded {}
+
+/// Fails with `SizeLimitExceeded` if `size` is over `limit`.
+pub fn check_size(
+    what: impl Into<String>,
+    size: usize,
+    limit: u64,
+) -> Result<(), SizeLimitExceeded> {
+    let size = size as u64;
+    if size > limit {
+        return Err(SizeLimitExceeded {
+            what: what.into(),
+            size,
+            limit,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use tfhe::prelude::*;
+use tfhe::zk::CompactPkeCrs;
+use tfhe::{CompactPublicKey, FheBool, FheUint32};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::batch::{BatchHeader, EncryptedOrders};
+use crate::common::Orders;
+use crate::error::DarkpoolError;
+use crate::proofs::{OrderFieldBounds, verify_order_batch};
+
+/// Which pairs of orders cross.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[versionize(MatchPolicyVersions)]
+pub enum MatchPolicy {
+    /// Prices are equal and sides are opposite.
+    #[default]
+    PriceAndSide,
+    /// Additionally, both orders are for the same asset pair.
+    AssetsPriceAndSide,
+}
+
+#[derive(VersionsDispatch)]
+pub enum MatchPolicyVersions {
+    V0(MatchPolicy),
+}
+
+/// How much of the comparison matrix the evaluating party sends back.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(ResultModeVersions)]
+pub enum ResultMode {
+    /// One `FheBool` per pair of orders, as returned by `match_orders`.
+    FullMatrix,
+    /// A single `FheBool`: whether any pair matches.
+    SingleBit,
+    /// One `FheUint32` per encrypted order: 1 + the index of the first book
+    /// order it matches, or 0 if it matches none.
+    Index,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ResultModeVersions {
+    V0(ResultMode),
+}
+
+impl ResultMode {
+    /// Every result mode, from the one revealing most to the one revealing least.
+    pub const ALL: &'static [ResultMode] = &[
+        ResultMode::FullMatrix,
+        ResultMode::Index,
+        ResultMode::SingleBit,
+    ];
+}
+
+/// Compares every encrypted order against every plaintext order of `book`.
+///
+/// An order matches when prices are equal and sides are opposite, and it is
+/// within the bounds it was verified against, if any. Result
+/// `i * book.order.len() + j` tells whether encrypted order `i` matches `book.order[j]`.
+/// The owner's server key must be installed with `set_server_key`.
+pub fn match_orders(encrypted: &EncryptedOrders, book: &Orders) -> Vec<FheBool> {
+    match_orders_with_policy(encrypted, book, MatchPolicy::PriceAndSide)
+}
+
+/// `match_orders`, with the orders that cross decided by `policy`.
+pub fn match_orders_with_policy(
+    encrypted: &EncryptedOrders,
+    book: &Orders,
+    policy: MatchPolicy,
+) -> Vec<FheBool> {
+    let mut results = Vec::with_capacity(encrypted.len() * book.order.len());
+    for i in 0..encrypted.len() {
+        for order in &book.order {
+            let eq_price = encrypted.price[i].eq(order.price);
+            let side_opposite = encrypted.side[i].ne(order.a_for_b);
+            let is_match = match policy {
+                MatchPolicy::PriceAndSide => eq_price & side_opposite,
+                MatchPolicy::AssetsPriceAndSide => {
+                    encrypted.asset_a[i].eq(order.asset_a)
+                        & encrypted.asset_b[i].eq(order.asset_b)
+                        & eq_price
+                        & side_opposite
+                }
+            };
+            let is_match = match &encrypted.in_bounds {
+                Some(in_bounds) => is_match & &in_bounds[i],
+                None => is_match,
+            };
+            results.push(is_match);
+        }
+    }
+    results
+}
+
+/// Folds a comparison matrix into whether any pair matches.
+pub fn any_match(results: &[FheBool]) -> FheBool {
+    results
+        .iter()
+        .fold(FheBool::encrypt_trivial(false), |any, result| &any | result)
+}
+
+/// Folds a comparison matrix into, per encrypted order, 1 + the index of the
+/// first book order it matches, or 0 if it matches none. An empty book yields
+/// no indices.
+pub fn first_match_indices(results: &[FheBool], book_len: usize) -> Vec<FheUint32> {
+    if book_len == 0 {
+        return Vec::new();
+    }
+    results
+        .chunks(book_len)
+        .map(|row| {
+            row.iter().enumerate().rev().fold(
+                FheUint32::encrypt_trivial(0u32),
+                |index, (j, result)| {
+                    result.select(&FheUint32::encrypt_trivial(j as u32 + 1), &index)
+                },
+            )
+        })
+        .collect()
+}
+
+/// Verifies the proofs of an incoming order batch, then matches it against `book`.
+///
+/// Nothing is evaluated unless every proof checks out.
+pub fn verify_and_match(
+    data: &[u8],
+    expected: &BatchHeader,
+    bounds: &OrderFieldBounds,
+    public_key: &CompactPublicKey,
+    crs: &CompactPkeCrs,
+    book: &Orders,
+) -> Result<Vec<FheBool>, DarkpoolError> {
+    let encrypted = verify_order_batch(data, expected, bounds, public_key, crs)?;
+    Ok(match_orders(&encrypted, book))
+}
@@ -0,0 +1,333 @@
+use serde::{Deserialize, Serialize};
+use tfhe::prelude::*;
+use tfhe::zk::{CompactPkeCrs, ZkComputeLoad};
+use tfhe::{
+    CompactCiphertextListBuilder, CompactCiphertextListExpander, CompactPublicKey, FheBool,
+    FheTypes, FheUint8, FheUint16, FheUint32, ProvenCompactCiphertextList,
+};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use crate::batch::{BatchHeader, EncryptedOrders, FIELDS_PER_ORDER};
+use crate::common::{Orders, safe_deserialize_item_with_limit, safe_serialize_item_with_limit};
+use crate::config::DarkpoolConfig;
+use crate::error::DarkpoolError;
+use crate::limits::SerializationLimits;
+use crate::protocol::PayloadType;
+
+/// Largest number of plaintext bits covered by a single proof.
+///
+/// Batches encrypting more bits than this are split across several proofs.
+pub const CRS_MAX_BITS: usize = 512;
+
+/// Generates the common reference string shared by provers and verifiers.
+///
+/// This belongs to an offline setup phase; every party must use the same CRS.
+pub fn generate_crs(config: &DarkpoolConfig) -> Result<CompactPkeCrs, DarkpoolError> {
+    Ok(CompactPkeCrs::from_config(
+        config.tfhe_config(),
+        CRS_MAX_BITS,
+    )?)
+}
+
+/// Bit width an integer field is encrypted with.
+///
+/// The proof shows every ciphertext encrypts a value of its declared width, so the
+/// width is the range bound a verifier can rely on.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(FieldWidthVersions)]
+pub enum FieldWidth {
+    U8,
+    U16,
+    U32,
+}
+
+#[derive(VersionsDispatch)]
+pub enum FieldWidthVersions {
+    V0(FieldWidth),
+}
+
+impl FieldWidth {
+    /// Largest value a field of this width can hold.
+    pub fn max_value(self) -> u32 {
+        match self {
+            FieldWidth::U8 => u8::MAX as u32,
+            FieldWidth::U16 => u16::MAX as u32,
+            FieldWidth::U32 => u32::MAX,
+        }
+    }
+
+    fn fhe_type(self) -> FheTypes {
+        match self {
+            FieldWidth::U8 => FheTypes::Uint8,
+            FieldWidth::U16 => FheTypes::Uint16,
+            FieldWidth::U32 => FheTypes::Uint32,
+        }
+    }
+
+    fn push(
+        self,
+        builder: &mut CompactCiphertextListBuilder,
+        field: &str,
+        value: u32,
+    ) -> Result<(), DarkpoolError> {
+        if value > self.max_value() {
+            return Err(DarkpoolError::InvalidOrder(format!(
+                "{} {} does not fit in {:?}",
+                field, value, self
+            )));
+        }
+        match self {
+            FieldWidth::U8 => builder.push(value as u8),
+            FieldWidth::U16 => builder.push(value as u16),
+            FieldWidth::U32 => builder.push(value),
+        };
+        Ok(())
+    }
+
+    fn get(
+        self,
+        expander: &CompactCiphertextListExpander,
+        field: &str,
+        index: usize,
+    ) -> Result<FheUint32, DarkpoolError> {
+        check_kind(expander, field, index, self.fhe_type())?;
+        let missing =
+            || DarkpoolError::ProtocolViolation(format!("missing {} at index {}", field, index));
+        Ok(match self {
+            FieldWidth::U8 => {
+                FheUint32::cast_from(expander.get::<FheUint8>(index)?.ok_or_else(missing)?)
+            }
+            FieldWidth::U16 => {
+                FheUint32::cast_from(expander.get::<FheUint16>(index)?.ok_or_else(missing)?)
+            }
+            FieldWidth::U32 => expander.get::<FheUint32>(index)?.ok_or_else(missing)?,
+        })
+    }
+}
+
+/// Per-field range bounds an evaluator requires of incoming order batches.
+///
+/// Widths are covered by the proofs, so batches breaking them are rejected.
+/// `max_price` is finer than any width and cannot be proven: the evaluator
+/// compares prices against it homomorphically instead, and orders above it
+/// never match.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(OrderFieldBoundsVersions)]
+pub struct OrderFieldBounds {
+    /// Width of `asset_a` and `asset_b`.
+    pub asset: FieldWidth,
+    pub price: FieldWidth,
+    /// Largest price an order may carry.
+    pub max_price: u32,
+}
+
+#[derive(VersionsDispatch)]
+pub enum OrderFieldBoundsVersions {
+    V0(OrderFieldBounds),
+}
+
+impl OrderFieldBounds {
+    /// Whether `max_price` is tighter than the price width, and so has to be
+    /// checked on the ciphertexts.
+    fn checks_max_price(&self) -> bool {
+        self.max_price < self.price.max_value()
+    }
+}
+
+impl Default for OrderFieldBounds {
+    fn default() -> Self {
+        Self {
+            asset: FieldWidth::U16,
+            price: FieldWidth::U32,
+            max_price: 1_000_000,
+        }
+    }
+}
+
+/// Wire format of a proven order upload.
+///
+/// The header and bounds are bound into the proofs as metadata, so neither can be
+/// swapped without invalidating them.
+#[derive(Serialize, Deserialize, Versionize)]
+#[versionize(ProvenOrderBatchVersions)]
+pub struct ProvenOrderBatch {
+    pub header: BatchHeader,
+    pub bounds: OrderFieldBounds,
+    pub ciphertexts: Vec<u8>,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ProvenOrderBatchVersions {
+    V0(ProvenOrderBatch),
+}
+
+fn proof_metadata(
+    header: &BatchHeader,
+    bounds: &OrderFieldBounds,
+) -> Result<Vec<u8>, DarkpoolError> {
+    bincode::serialize(&(header, bounds)).map_err(DarkpoolError::Serialization)
+}
+
+fn check_kind(
+    expander: &CompactCiphertextListExpander,
+    field: &str,
+    index: usize,
+    expected: FheTypes,
+) -> Result<(), DarkpoolError> {
+    match expander.get_kind_of(index) {
+        Some(kind) if kind == expected => Ok(()),
+        found => Err(DarkpoolError::ProtocolViolation(format!(
+            "{} at index {} is {:?}, expected {:?}",
+            field, index, found, expected
+        ))),
+    }
+}
+
+impl ProvenOrderBatch {
+    /// Encrypts orders with a proof that every field is well formed and within `bounds`.
+    ///
+    /// Values outside the bounds are refused here, before any proof is computed.
+    pub fn prove(
+        orders: &Orders,
+        public_key: &CompactPublicKey,
+        crs: &CompactPkeCrs,
+        header: &BatchHeader,
+        bounds: &OrderFieldBounds,
+    ) -> Result<Self, DarkpoolError> {
+        let mut builder = ProvenCompactCiphertextList::builder(public_key);
+        for order in &orders.order {
+            bounds.asset.push(&mut builder, "asset_a", order.asset_a)?;
+            bounds.asset.push(&mut builder, "asset_b", order.asset_b)?;
+            if order.price > bounds.max_price {
+                return Err(DarkpoolError::InvalidOrder(format!(
+                    "price {} is above the maximum of {}",
+                    order.price, bounds.max_price
+                )));
+            }
+            bounds.price.push(&mut builder, "price", order.price)?;
+            builder.push(order.a_for_b);
+        }
+        let list = builder.build_with_proof_packed(
+            crs,
+            &proof_metadata(header, bounds)?,
+            ZkComputeLoad::Proof,
+        )?;
+
+        Ok(Self {
+            header: *header,
+            bounds: *bounds,
+            ciphertexts: safe_serialize_item_with_limit(
+                &list,
+                SerializationLimits::default().proven_order_batch,
+            )?,
+        })
+    }
+
+    /// Verifies the proofs and expands the batch into per-field ciphertexts.
+    ///
+    /// The batch is rejected if it was made for another key, declares other bounds
+    /// than `bounds`, fails proof verification, or holds ciphertexts of unexpected
+    /// types. Prices are then checked against `bounds.max_price`, so that orders
+    /// above it never match. The owner's server key must be installed with
+    /// `set_server_key`.
+    pub fn verify(
+        &self,
+        expected: &BatchHeader,
+        bounds: &OrderFieldBounds,
+        public_key: &CompactPublicKey,
+        crs: &CompactPkeCrs,
+    ) -> Result<EncryptedOrders, DarkpoolError> {
+        self.header.check(expected)?;
+        if self.bounds != *bounds {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "batch declares field bounds {:?}, but {:?} are required",
+                self.bounds, bounds
+            )));
+        }
+
+        let list: ProvenCompactCiphertextList =
+            safe_deserialize_item_with_limit(&self.ciphertexts, self.ciphertexts.len() as u64)?;
+        if !list.len().is_multiple_of(FIELDS_PER_ORDER) {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "order batch holds {} ciphertexts, expected a multiple of {}",
+                list.len(),
+                FIELDS_PER_ORDER
+            )));
+        }
+        let expander = list
+            .verify_and_expand(
+                crs,
+                public_key,
+                &proof_metadata(&self.header, &self.bounds)?,
+            )
+            .map_err(|e| {
+                DarkpoolError::ProtocolViolation(format!(
+                    "order batch proof verification failed: {}",
+                    e
+                ))
+            })?;
+
+        let count = list.len() / FIELDS_PER_ORDER;
+        let mut orders = EncryptedOrders {
+            asset_a: Vec::with_capacity(count),
+            asset_b: Vec::with_capacity(count),
+            price: Vec::with_capacity(count),
+            side: Vec::with_capacity(count),
+            in_bounds: None,
+        };
+        for i in 0..count {
+            let base = i * FIELDS_PER_ORDER;
+            orders
+                .asset_a
+                .push(bounds.asset.get(&expander, "asset_a", base)?);
+            orders
+                .asset_b
+                .push(bounds.asset.get(&expander, "asset_b", base + 1)?);
+            orders
+                .price
+                .push(bounds.price.get(&expander, "price", base + 2)?);
+            check_kind(&expander, "side", base + 3, FheTypes::Bool)?;
+            orders
+                .side
+                .push(expander.get::<FheBool>(base + 3)?.ok_or_else(|| {
+                    DarkpoolError::ProtocolViolation(format!("missing side at index {}", base + 3))
+                })?);
+        }
+        if bounds.checks_max_price() {
+            orders.in_bounds = Some(
+                orders
+                    .price
+                    .iter()
+                    .map(|price| price.le(bounds.max_price))
+                    .collect(),
+            );
+        }
+        Ok(orders)
+    }
+}
+
+/// Proves and serializes a list of orders as one upload; see `ProvenOrderBatch::prove`.
+pub fn serialize_proven_order_batch(
+    orders: &Orders,
+    public_key: &CompactPublicKey,
+    crs: &CompactPkeCrs,
+    header: &BatchHeader,
+    bounds: &OrderFieldBounds,
+) -> Result<Vec<u8>, DarkpoolError> {
+    let batch = ProvenOrderBatch::prove(orders, public_key, crs, header, bounds)?;
+    bincode::serialize(&batch).map_err(DarkpoolError::Serialization)
+}
+
+/// Deserializes and verifies a proven upload; see `ProvenOrderBatch::verify`.
+pub fn verify_order_batch(
+    data: &[u8],
+    expected: &BatchHeader,
+    bounds: &OrderFieldBounds,
+    public_key: &CompactPublicKey,
+    crs: &CompactPkeCrs,
+) -> Result<EncryptedOrders, DarkpoolError> {
+    SerializationLimits::default().check_payload(PayloadType::ProvenOrderBatch, data.len())?;
+    let batch: ProvenOrderBatch =
+        bincode::deserialize(data).map_err(|e| DarkpoolError::ProtocolViolation(e.to_string()))?;
+    batch.verify(expected, bounds, public_key, crs)
+}
@@ -0,0 +1,616 @@
+pub mod codec;
+pub mod frame;
+pub mod replay;
+pub mod text;
+
+use std::convert::Infallible;
+use std::io::{self, Cursor, Read, Write};
+
+use ed25519_dalek::SIGNATURE_LENGTH;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tfhe::named::Named;
+use tfhe::safe_serialization::{safe_deserialize, safe_serialize};
+use tfhe::{CompactPublicKey, ServerKey};
+use tfhe_versionable::{Upgrade, Version, Versionize, VersionsDispatch};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::SyncIoBridge;
+
+use crate::batch::{BatchHeader, OrderBatch, ResultBatch};
+use crate::config::DarkpoolConfig;
+use crate::error::DarkpoolError;
+use crate::identity::{EnvelopeSignature, Identity, PeerId};
+use crate::keys::{KeyFingerprint, KeySet};
+use crate::limits::SerializationLimits;
+use crate::proofs::ProvenOrderBatch;
+use crate::session::handshake::Hello;
+use codec::Codec;
+
+/// Version of the wire protocol spoken by this build.
+///
+/// Bumped whenever a peer running an older build could no longer decode our messages.
+pub const PROTOCOL_VERSION: u16 = 7;
+
+/// Kind of payload following a header, so receivers can route before decoding it.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(PayloadTypeVersions)]
+pub enum PayloadType {
+    KeyAnnouncement,
+    OrderBatch,
+    ProvenOrderBatch,
+    MatchResults,
+    /// Named `Error` in the text encoding.
+    #[serde(rename = "Error")]
+    Abort,
+    Hello,
+}
+
+#[derive(VersionsDispatch)]
+pub enum PayloadTypeVersions {
+    V0(PayloadType),
+}
+
+/// Fixed-layout prefix of every protocol message.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(HeaderVersions)]
+pub struct Header {
+    pub version: u16,
+    pub session_id: u64,
+    pub payload_type: PayloadType,
+    /// How the body following the header is encoded.
+    pub codec: Codec,
+    /// Position of the message among those its sender sent in the session,
+    /// starting at 0.
+    pub sequence: u64,
+    /// Random value making every envelope unique, even across sessions.
+    pub nonce: u64,
+}
+
+/// Header of protocol version 1, which had no codec.
+#[derive(Serialize, Deserialize, Version, Clone, Copy)]
+pub struct HeaderV0 {
+    pub version: u16,
+    pub session_id: u64,
+    pub payload_type: PayloadType,
+}
+
+impl Upgrade<HeaderV1> for HeaderV0 {
+    type Error = Infallible;
+
+    fn upgrade(self) -> Result<HeaderV1, Self::Error> {
+        Ok(HeaderV1 {
+            version: self.version,
+            session_id: self.session_id,
+            payload_type: self.payload_type,
+            codec: Codec::None,
+        })
+    }
+}
+
+/// Header of protocol versions 2 and 3, which had no sequence number or nonce.
+#[derive(Serialize, Deserialize, Version, Clone, Copy)]
+pub struct HeaderV1 {
+    pub version: u16,
+    pub session_id: u64,
+    pub payload_type: PayloadType,
+    pub codec: Codec,
+}
+
+impl Upgrade<Header> for HeaderV1 {
+    type Error = Infallible;
+
+    fn upgrade(self) -> Result<Header, Self::Error> {
+        Ok(Header {
+            version: self.version,
+            session_id: self.session_id,
+            payload_type: self.payload_type,
+            codec: self.codec,
+            sequence: 0,
+            nonce: 0,
+        })
+    }
+}
+
+#[derive(VersionsDispatch)]
+pub enum HeaderVersions {
+    V0(HeaderV0),
+    V1(HeaderV1),
+    V2(Header),
+}
+
+impl Named for Header {
+    const NAME: &'static str = "fhe_darkpool::protocol::Header";
+}
+
+impl Header {
+    /// Decodes a header written at any protocol version, upgrading older layouts,
+    /// without checking the version.
+    ///
+    /// Receivers use `Envelope::decode_header` instead, which rejects other
+    /// versions; this tells what an older peer sent.
+    pub fn decode(data: &[u8], limits: &SerializationLimits) -> Result<Header, DarkpoolError> {
+        read_any_header(Cursor::new(data), limits)
+    }
+}
+
+/// A party's public keys, sent before any ciphertext so the peer can evaluate and verify.
+#[derive(Serialize, Deserialize, Versionize)]
+#[versionize(KeyAnnouncementVersions)]
+pub struct KeyAnnouncement {
+    pub config: DarkpoolConfig,
+    pub epoch: u64,
+    pub key_fingerprint: KeyFingerprint,
+    pub server_key: ServerKey,
+    pub public_key: CompactPublicKey,
+}
+
+#[derive(VersionsDispatch)]
+pub enum KeyAnnouncementVersions {
+    V0(KeyAnnouncement),
+}
+
+impl KeyAnnouncement {
+    pub fn new(keys: &KeySet) -> Self {
+        Self {
+            config: keys.config,
+            epoch: keys.epoch,
+            key_fingerprint: keys.fingerprint,
+            server_key: keys.server_key.clone(),
+            public_key: keys.public_key.clone(),
+        }
+    }
+
+    /// Header the announcing party stamps on its batches, and expects on results.
+    pub fn batch_header(&self) -> BatchHeader {
+        BatchHeader {
+            profile: self.config.profile,
+            epoch: self.epoch,
+            key_fingerprint: self.key_fingerprint,
+        }
+    }
+
+    /// Recomputes the fingerprint of the announced server key and compares it.
+    pub fn verify_fingerprint(&self) -> Result<(), DarkpoolError> {
+        let fingerprint = KeyFingerprint::of_server_key(&self.server_key)?;
+        if fingerprint != self.key_fingerprint {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "announced server key hashes to {}, but the announcement claims {}",
+                fingerprint, self.key_fingerprint
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Sent instead of an expected reply when a peer cannot proceed.
+#[derive(Serialize, Deserialize, Versionize, Clone, PartialEq, Eq, Debug)]
+#[versionize(ErrorMessageVersions)]
+pub struct ErrorMessage {
+    pub message: String,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ErrorMessageVersions {
+    V0(ErrorMessage),
+}
+
+/// Every message two parties exchange.
+#[derive(Serialize, Deserialize, Versionize)]
+#[versionize(MessageVersions)]
+pub enum Message {
+    KeyAnnouncement(KeyAnnouncement),
+    OrderBatch(OrderBatch),
+    ProvenOrderBatch(ProvenOrderBatch),
+    MatchResults(ResultBatch),
+    /// The sender gives up on the session.
+    Abort(ErrorMessage),
+    Hello(Hello),
+}
+
+#[derive(VersionsDispatch)]
+pub enum MessageVersions {
+    V0(Message),
+}
+
+impl Named for Message {
+    const NAME: &'static str = "fhe_darkpool::protocol::Message";
+}
+
+impl Message {
+    pub fn payload_type(&self) -> PayloadType {
+        match self {
+            Message::KeyAnnouncement(_) => PayloadType::KeyAnnouncement,
+            Message::OrderBatch(_) => PayloadType::OrderBatch,
+            Message::ProvenOrderBatch(_) => PayloadType::ProvenOrderBatch,
+            Message::MatchResults(_) => PayloadType::MatchResults,
+            Message::Abort(_) => PayloadType::Abort,
+            Message::Hello(_) => PayloadType::Hello,
+        }
+    }
+}
+
+/// Returns a fresh random session id.
+pub fn new_session_id() -> u64 {
+    random()
+}
+
+/// A message together with its header, as sent on the wire.
+///
+/// On the wire the header is followed by the body and then by the sender's
+/// signature over both, so a receiver can read and check the header without
+/// decoding the payload.
+pub struct Envelope {
+    pub header: Header,
+    pub message: Message,
+}
+
+/// An envelope received from a peer, with the signature that authenticated it.
+pub struct SignedEnvelope {
+    pub envelope: Envelope,
+    pub signature: EnvelopeSignature,
+    /// SHA-256 digest of the encoded body, as covered by the signature.
+    pub payload_digest: [u8; 32],
+}
+
+impl SignedEnvelope {
+    pub fn signer(&self) -> PeerId {
+        self.signature.signer
+    }
+
+    /// Rejects the envelope unless it was signed by `expected`.
+    ///
+    /// Decoding only proves that the signer's key signed the envelope; this is
+    /// where the receiver checks that the signer is the counterparty it expects.
+    pub fn check_signer(&self, expected: &PeerId) -> Result<(), DarkpoolError> {
+        if self.signer() != *expected {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "envelope is signed by {}, expected {}",
+                self.signer(),
+                expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Envelope {
+    pub fn new(session_id: u64, message: Message) -> Self {
+        Self {
+            header: Header {
+                version: PROTOCOL_VERSION,
+                session_id,
+                payload_type: message.payload_type(),
+                codec: Codec::None,
+                sequence: 0,
+                nonce: random(),
+            },
+            message,
+        }
+    }
+
+    /// Sets the position of the envelope in its sender's stream; see `Sequencer`.
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.header.sequence = sequence;
+        self
+    }
+
+    /// Replaces the random nonce, e.g. to reproduce an envelope byte for byte.
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.header.nonce = nonce;
+        self
+    }
+
+    /// Encodes the body with `codec`, typically the one negotiated for the session.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.header.codec = codec;
+        self
+    }
+
+    /// Encodes and signs the envelope, failing if the message is over its limit in `limits`.
+    pub fn encode(
+        &self,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<Vec<u8>, DarkpoolError> {
+        let mut buf = Vec::new();
+        safe_serialize(&self.header, &mut buf, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        let body = codec::encode_body(self.header.codec, &self.message, limits)?;
+        let signature = identity.sign_envelope(&buf, &Sha256::digest(&body).into());
+        buf.extend(body);
+        safe_serialize(&signature, &mut buf, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        Ok(buf)
+    }
+
+    /// Reads only the header of an encoded envelope and checks its protocol version.
+    ///
+    /// The signature is not checked, so nothing in the header can be trusted yet.
+    pub fn decode_header(
+        data: &[u8],
+        limits: &SerializationLimits,
+    ) -> Result<Header, DarkpoolError> {
+        read_header(Cursor::new(data), limits)
+    }
+
+    /// Decodes an envelope and verifies its signature.
+    ///
+    /// Other protocol versions are rejected before touching the payload, and the
+    /// body is checked against the limit for the payload type announced in the
+    /// header, so an oversized message is rejected before any of it is decoded.
+    /// `data` must hold the envelope alone: trailing bytes are rejected.
+    pub fn decode(
+        data: &[u8],
+        limits: &SerializationLimits,
+    ) -> Result<SignedEnvelope, DarkpoolError> {
+        let mut cursor = Cursor::new(data);
+        let header = read_header(&mut cursor, limits)?;
+        // What follows is the body, then the signature trailer.
+        let rest = data.len() - cursor.position() as usize;
+        limits.check_payload(
+            header.payload_type,
+            rest.saturating_sub(signature_len(limits)?),
+        )?;
+        let mut cursor = Cursor::new(data);
+        let envelope = read_signed(&mut cursor, limits)?;
+        let trailing = data.len() - cursor.position() as usize;
+        if trailing > 0 {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "{} bytes after the signature",
+                trailing
+            )));
+        }
+        Ok(envelope)
+    }
+
+    /// Streams the signed envelope into `writer`, without encoding it in memory
+    /// first unless it is compressed.
+    ///
+    /// I/O failures of `writer` are reported as `DarkpoolError::Transport`.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: W,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<(), DarkpoolError> {
+        let mut writer = TrackedIo::new(writer);
+        self.write_signed(&mut writer, identity, limits)
+            .map_err(|e| writer.blame(e))
+    }
+
+    fn write_signed<W: Write>(
+        &self,
+        mut writer: W,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<(), DarkpoolError> {
+        let mut header = Vec::new();
+        safe_serialize(&self.header, &mut header, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        writer.write_all(&header)?;
+
+        let mut body = Tee::new(&mut writer, Sha256::new());
+        codec::write_body(self.header.codec, &self.message, &mut body, limits)?;
+        let payload_digest = body.copy.finalize().into();
+
+        let signature = identity.sign_envelope(&header, &payload_digest);
+        safe_serialize(&signature, &mut writer, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        Ok(())
+    }
+
+    /// Reads one envelope from `reader`, consuming exactly its bytes, and verifies
+    /// its signature.
+    ///
+    /// The body is read with the limit for the announced payload type, so a peer
+    /// cannot make the receiver buffer more than that. I/O failures of `reader`,
+    /// including a peer hanging up mid-message, are reported as
+    /// `DarkpoolError::Transport`.
+    pub fn read_from<R: Read>(
+        reader: R,
+        limits: &SerializationLimits,
+    ) -> Result<SignedEnvelope, DarkpoolError> {
+        let mut reader = TrackedIo::new(reader);
+        read_signed(&mut reader, limits).map_err(|e| reader.blame(e))
+    }
+
+    /// Async counterpart of `write_to`; serialization runs on the blocking pool.
+    ///
+    /// Returns the writer so the connection can be reused.
+    pub async fn write_to_async<W>(
+        self,
+        writer: W,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<W, DarkpoolError>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let identity = identity.clone();
+        let limits = *limits;
+        let mut bridge = SyncIoBridge::new(writer);
+        tokio::task::spawn_blocking(move || {
+            self.write_to(&mut bridge, &identity, &limits)?;
+            bridge.flush().map_err(DarkpoolError::Transport)?;
+            Ok(bridge.into_inner())
+        })
+        .await
+        .map_err(|e| DarkpoolError::Transport(e.into()))?
+    }
+
+    /// Async counterpart of `read_from`; deserialization runs on the blocking pool.
+    ///
+    /// Returns the reader so the next envelope can be read from it.
+    pub async fn read_from_async<R>(
+        reader: R,
+        limits: &SerializationLimits,
+    ) -> Result<(SignedEnvelope, R), DarkpoolError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let limits = *limits;
+        let mut bridge = SyncIoBridge::new(reader);
+        tokio::task::spawn_blocking(move || {
+            let envelope = Self::read_from(&mut bridge, &limits)?;
+            Ok((envelope, bridge.into_inner()))
+        })
+        .await
+        .map_err(|e| DarkpoolError::Transport(e.into()))?
+    }
+}
+
+fn read_header<R: Read>(reader: R, limits: &SerializationLimits) -> Result<Header, DarkpoolError> {
+    let header = read_any_header(reader, limits)?;
+    check_version(&header)?;
+    Ok(header)
+}
+
+fn read_any_header<R: Read>(
+    reader: R,
+    limits: &SerializationLimits,
+) -> Result<Header, DarkpoolError> {
+    safe_deserialize(reader, limits.header).map_err(DarkpoolError::ProtocolViolation)
+}
+
+fn read_body<R: Read>(
+    header: Header,
+    reader: R,
+    limits: &SerializationLimits,
+) -> Result<Envelope, DarkpoolError> {
+    let message = codec::read_body(header.codec, header.payload_type, reader, limits)?;
+    if message.payload_type() != header.payload_type {
+        return Err(DarkpoolError::ProtocolViolation(format!(
+            "header announces a {:?} payload, but the body holds {:?}",
+            header.payload_type,
+            message.payload_type()
+        )));
+    }
+    Ok(Envelope { header, message })
+}
+
+/// Size of the signature trailer, which is the same for every signer.
+fn signature_len(limits: &SerializationLimits) -> Result<usize, DarkpoolError> {
+    let trailer = EnvelopeSignature {
+        signer: PeerId([0; 32]),
+        signature: vec![0; SIGNATURE_LENGTH],
+    };
+    let mut buf = Vec::new();
+    safe_serialize(&trailer, &mut buf, limits.header).map_err(DarkpoolError::Serialization)?;
+    Ok(buf.len())
+}
+
+fn read_signed<R: Read>(
+    mut reader: R,
+    limits: &SerializationLimits,
+) -> Result<SignedEnvelope, DarkpoolError> {
+    let mut header_bytes = Tee::new(&mut reader, Vec::new());
+    let header = read_header(&mut header_bytes, limits)?;
+    let header_bytes = header_bytes.copy;
+
+    let mut body = Tee::new(&mut reader, Sha256::new());
+    let envelope = read_body(header, &mut body, limits)?;
+    let payload_digest = body.copy.finalize().into();
+
+    let signature: EnvelopeSignature =
+        safe_deserialize(reader, limits.header).map_err(DarkpoolError::ProtocolViolation)?;
+    signature.verify(&header_bytes, &payload_digest)?;
+    Ok(SignedEnvelope {
+        envelope,
+        signature,
+        payload_digest,
+    })
+}
+
+fn check_version(header: &Header) -> Result<(), DarkpoolError> {
+    if header.version != PROTOCOL_VERSION {
+        return Err(DarkpoolError::VersionMismatch {
+            peer: header.version,
+            local: PROTOCOL_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Wraps a reader or writer and remembers the last I/O error it returned.
+///
+/// tfhe flattens I/O errors into its own, so this is how a failed read is told
+/// apart from bytes that do not decode.
+struct TrackedIo<T> {
+    inner: T,
+    error: Option<io::Error>,
+}
+
+impl<T> TrackedIo<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, error: None }
+    }
+
+    /// Turns `e` into a transport error if the underlying stream failed.
+    fn blame(&mut self, e: DarkpoolError) -> DarkpoolError {
+        match self.error.take() {
+            Some(io_error) => DarkpoolError::Transport(io_error),
+            None => e,
+        }
+    }
+
+    fn track<V>(&mut self, result: io::Result<V>) -> io::Result<V> {
+        if let Err(e) = &result {
+            self.error = Some(io::Error::new(e.kind(), e.to_string()));
+        }
+        result
+    }
+}
+
+impl<T: Read> Read for TrackedIo<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.track(result)
+    }
+}
+
+impl<T: Write> Write for TrackedIo<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.track(result)
+    }
+}
+
+/// Passes bytes through to `inner` while copying them into `copy`.
+///
+/// Used to hash an envelope body, or keep its header bytes, as it is streamed.
+struct Tee<T, C> {
+    inner: T,
+    copy: C,
+}
+
+impl<T, C: Write> Tee<T, C> {
+    fn new(inner: T, copy: C) -> Self {
+        Self { inner, copy }
+    }
+}
+
+impl<T: Read, C: Write> Read for Tee<T, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<T: Write, C: Write> Write for Tee<T, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.copy.write_all(&buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
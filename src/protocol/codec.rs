@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+use tfhe::safe_serialization::{safe_deserialize, safe_serialize};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+
+use super::{Message, PayloadType};
+use crate::error::DarkpoolError;
+use crate::limits::{SerializationLimits, check_size};
+
+/// zstd level used for compressed bodies; higher levels gain little on ciphertexts.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compressed bodies are read in chunks of this size, so a peer announcing a
+/// large body cannot make the receiver allocate it before sending it.
+const READ_CHUNK: usize = 1 << 16;
+
+/// How the body of an envelope is encoded on the wire.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[versionize(CodecVersions)]
+pub enum Codec {
+    /// The safe-serialized message, as is.
+    #[default]
+    None,
+    /// The safe-serialized message compressed with zstd, prefixed with its
+    /// compressed length as a little-endian `u64`.
+    Zstd,
+}
+
+#[derive(VersionsDispatch)]
+pub enum CodecVersions {
+    V0(Codec),
+}
+
+impl Codec {
+    /// Every codec this build can read and write, most preferred first.
+    pub const SUPPORTED: &'static [Codec] = &[Codec::Zstd, Codec::None];
+
+    /// Picks the first of `ours` the peer also supports, falling back to `None`.
+    pub fn negotiate(ours: &[Codec], theirs: &[Codec]) -> Codec {
+        ours.iter()
+            .copied()
+            .find(|codec| theirs.contains(codec))
+            .unwrap_or(Codec::None)
+    }
+}
+
+/// Encodes `message` with `codec`, checking the result against the payload limit.
+///
+/// For compressed bodies the uncompressed size is checked too, since that is
+/// what the receiver will have to decode.
+pub(super) fn encode_body(
+    codec: Codec,
+    message: &Message,
+    limits: &SerializationLimits,
+) -> Result<Vec<u8>, DarkpoolError> {
+    let payload_type = message.payload_type();
+    let mut raw = Vec::new();
+    safe_serialize(message, &mut raw, u64::MAX).map_err(DarkpoolError::Serialization)?;
+    limits.check_payload(payload_type, raw.len())?;
+
+    let body = match codec {
+        Codec::None => raw,
+        Codec::Zstd => {
+            let compressed = zstd::encode_all(raw.as_slice(), ZSTD_LEVEL)?;
+            let mut body = Vec::with_capacity(8 + compressed.len());
+            body.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+            body.extend_from_slice(&compressed);
+            body
+        }
+    };
+    limits.check_payload(payload_type, body.len())?;
+    Ok(body)
+}
+
+/// Streams `message` into `writer`; compressed bodies are built in memory first,
+/// since their length prefix is only known once compressed.
+pub(super) fn write_body<W: Write>(
+    codec: Codec,
+    message: &Message,
+    mut writer: W,
+    limits: &SerializationLimits,
+) -> Result<(), DarkpoolError> {
+    match codec {
+        Codec::None => safe_serialize(message, writer, limits.for_payload(message.payload_type()))
+            .map_err(DarkpoolError::Serialization)?,
+        Codec::Zstd => writer.write_all(&encode_body(codec, message, limits)?)?,
+    }
+    Ok(())
+}
+
+/// Reads a message body encoded with `codec`, consuming exactly its bytes.
+///
+/// Both the compressed and the decompressed size are bounded by the limit for
+/// `payload_type`.
+pub(super) fn read_body<R: Read>(
+    codec: Codec,
+    payload_type: PayloadType,
+    mut reader: R,
+    limits: &SerializationLimits,
+) -> Result<Message, DarkpoolError> {
+    let limit = limits.for_payload(payload_type);
+    match codec {
+        Codec::None => safe_deserialize(reader, limit).map_err(DarkpoolError::ProtocolViolation),
+        Codec::Zstd => {
+            let truncated =
+                |e| DarkpoolError::ProtocolViolation(format!("truncated compressed body: {}", e));
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len).map_err(truncated)?;
+            let len = u64::from_le_bytes(len);
+            check_size(
+                format!("compressed {:?}", payload_type),
+                len as usize,
+                limit,
+            )?;
+
+            let mut compressed = Vec::new();
+            while (compressed.len() as u64) < len {
+                let start = compressed.len();
+                let chunk = READ_CHUNK.min((len - start as u64) as usize);
+                compressed.resize(start + chunk, 0);
+                reader
+                    .read_exact(&mut compressed[start..])
+                    .map_err(truncated)?;
+            }
+
+            let mut decoder = zstd::stream::read::Decoder::new(compressed.as_slice())
+                .map_err(|e| DarkpoolError::ProtocolViolation(e.to_string()))?;
+            let message =
+                safe_deserialize(&mut decoder, limit).map_err(DarkpoolError::ProtocolViolation)?;
+            // Compressed bytes are covered by the signature; what they decompress to
+            // must be the message alone.
+            let mut trailing = [0u8; 1];
+            let read = decoder
+                .read(&mut trailing)
+                .map_err(|e| DarkpoolError::ProtocolViolation(e.to_string()))?;
+            if read != 0 {
+                return Err(DarkpoolError::ProtocolViolation(
+                    "data after the message in the compressed body".to_string(),
+                ));
+            }
+            Ok(message)
+        }
+    }
+}
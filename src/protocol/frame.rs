@@ -0,0 +1,214 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Envelope, PayloadType, SignedEnvelope};
+use crate::error::DarkpoolError;
+use crate::identity::Identity;
+use crate::limits::{SerializationLimits, check_size};
+use crate::transport::{FrameRead, FrameWrite};
+
+/// Bytes before the envelope in every frame: the type byte and the length.
+const FRAME_HEADER_LEN: usize = 9;
+
+/// One encoded envelope, as carried by a frame.
+///
+/// On the wire a frame is a type byte, the length of the envelope as a
+/// little-endian `u64`, then the envelope itself. The type byte and length are
+/// checked before the envelope is read, so a malformed or oversized frame is
+/// rejected without buffering it or handing it to tfhe.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    pub payload_type: PayloadType,
+    /// The encoded envelope, as produced by `Envelope::encode`.
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    /// Encodes and signs `envelope` into a frame.
+    pub fn new(
+        envelope: &Envelope,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<Self, DarkpoolError> {
+        Ok(Self {
+            payload_type: envelope.header.payload_type,
+            data: envelope.encode(identity, limits)?,
+        })
+    }
+
+    /// Decodes the envelope, checking it is of the type the frame announced.
+    pub fn decode(&self, limits: &SerializationLimits) -> Result<SignedEnvelope, DarkpoolError> {
+        let envelope = Envelope::decode(&self.data, limits)?;
+        if envelope.envelope.header.payload_type != self.payload_type {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "{:?} frame holds a {:?} envelope",
+                self.payload_type, envelope.envelope.header.payload_type
+            )));
+        }
+        Ok(envelope)
+    }
+}
+
+/// Largest frame accepted for `payload_type`: a header, a body within the limit
+/// for that type, and a signature, itself no larger than a header.
+pub fn max_frame_len(limits: &SerializationLimits, payload_type: PayloadType) -> u64 {
+    limits
+        .header
+        .saturating_mul(2)
+        .saturating_add(limits.for_payload(payload_type))
+}
+
+/// Reads frames from a stream, rejecting malformed ones before reading their contents.
+pub struct FrameReader<R> {
+    reader: R,
+    limits: SerializationLimits,
+}
+
+impl<R> FrameReader<R> {
+    pub fn new(reader: R, limits: SerializationLimits) -> Self {
+        Self { reader, limits }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// I/O failures, including a stream ending mid-frame, are reported as
+/// `DarkpoolError::Transport`.
+impl<R: AsyncRead + Unpin + Send> FrameRead for FrameReader<R> {
+    async fn read_frame(&mut self) -> Result<Option<Frame>, DarkpoolError> {
+        let mut prefix = [0u8; FRAME_HEADER_LEN];
+        let read = self
+            .reader
+            .read(&mut prefix[..1])
+            .await
+            .map_err(DarkpoolError::Transport)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.reader
+            .read_exact(&mut prefix[1..])
+            .await
+            .map_err(DarkpoolError::Transport)?;
+
+        let payload_type = payload_type_from_byte(prefix[0]).ok_or_else(|| {
+            DarkpoolError::ProtocolViolation(format!("unknown frame type {:#04x}", prefix[0]))
+        })?;
+        let len = u64::from_le_bytes(prefix[1..].try_into().expect("8 length bytes"));
+        check_frame_len(
+            payload_type,
+            usize::try_from(len).unwrap_or(usize::MAX),
+            &self.limits,
+        )?;
+
+        // Grows with what actually arrives, rather than with what the peer announced.
+        let mut data = Vec::new();
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut data)
+            .await
+            .map_err(DarkpoolError::Transport)?;
+        if data.len() as u64 != len {
+            return Err(DarkpoolError::Transport(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "stream ended {} bytes into a {} byte frame",
+                    data.len(),
+                    len
+                ),
+            )));
+        }
+        Ok(Some(Frame { payload_type, data }))
+    }
+
+    fn limits(&self) -> SerializationLimits {
+        self.limits
+    }
+
+    fn set_limits(&mut self, limits: SerializationLimits) {
+        self.limits = limits;
+    }
+}
+
+/// Writes frames to a stream.
+pub struct FrameWriter<W> {
+    writer: W,
+    limits: SerializationLimits,
+}
+
+impl<W> FrameWriter<W> {
+    pub fn new(writer: W, limits: SerializationLimits) -> Self {
+        Self { writer, limits }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Every frame is flushed once written.
+impl<W: AsyncWrite + Unpin + Send> FrameWrite for FrameWriter<W> {
+    async fn write_frame(&mut self, frame: &Frame) -> Result<(), DarkpoolError> {
+        check_frame_len(frame.payload_type, frame.data.len(), &self.limits)?;
+        let mut prefix = [0u8; FRAME_HEADER_LEN];
+        prefix[0] = payload_type_byte(frame.payload_type);
+        prefix[1..].copy_from_slice(&(frame.data.len() as u64).to_le_bytes());
+
+        self.writer
+            .write_all(&prefix)
+            .await
+            .map_err(DarkpoolError::Transport)?;
+        self.writer
+            .write_all(&frame.data)
+            .await
+            .map_err(DarkpoolError::Transport)?;
+        self.writer.flush().await.map_err(DarkpoolError::Transport)
+    }
+
+    fn limits(&self) -> SerializationLimits {
+        self.limits
+    }
+
+    fn set_limits(&mut self, limits: SerializationLimits) {
+        self.limits = limits;
+    }
+}
+
+/// Rejects a frame of `len` bytes the peer would refuse as oversized.
+pub(crate) fn check_frame_len(
+    payload_type: PayloadType,
+    len: usize,
+    limits: &SerializationLimits,
+) -> Result<(), DarkpoolError> {
+    check_size(
+        format!("{:?} frame", payload_type),
+        len,
+        max_frame_len(limits, payload_type),
+    )?;
+    Ok(())
+}
+
+fn payload_type_byte(payload_type: PayloadType) -> u8 {
+    match payload_type {
+        PayloadType::KeyAnnouncement => 1,
+        PayloadType::OrderBatch => 2,
+        PayloadType::ProvenOrderBatch => 3,
+        PayloadType::MatchResults => 4,
+        PayloadType::Abort => 5,
+        PayloadType::Hello => 6,
+    }
+}
+
+fn payload_type_from_byte(byte: u8) -> Option<PayloadType> {
+    match byte {
+        1 => Some(PayloadType::KeyAnnouncement),
+        2 => Some(PayloadType::OrderBatch),
+        3 => Some(PayloadType::ProvenOrderBatch),
+        4 => Some(PayloadType::MatchResults),
+        5 => Some(PayloadType::Abort),
+        6 => Some(PayloadType::Hello),
+        _ => None,
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+
+use super::{Envelope, Header, Message};
+use crate::error::DarkpoolError;
+
+/// Stamps the envelopes a party sends in one session with increasing sequence numbers.
+pub struct Sequencer {
+    session_id: u64,
+    next: u64,
+}
+
+impl Sequencer {
+    pub fn new(session_id: u64) -> Self {
+        Self {
+            session_id,
+            next: 0,
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Wraps `message` in the next envelope of the session.
+    pub fn envelope(&mut self, message: Message) -> Envelope {
+        let envelope = Envelope::new(self.session_id, message).with_sequence(self.next);
+        self.next += 1;
+        envelope
+    }
+}
+
+/// Rejects envelopes that do not belong to the session, or were already received.
+///
+/// Headers are covered by the sender's signature, so a replayed envelope cannot
+/// be moved to another session or renumbered without failing verification. What
+/// is left to the receiver is checking it against the session state, which is
+/// what this guard does; it should see every envelope received from one peer.
+pub struct ReplayGuard {
+    session_id: u64,
+    last_sequence: Option<u64>,
+    nonces: HashSet<u64>,
+}
+
+impl ReplayGuard {
+    pub fn new(session_id: u64) -> Self {
+        Self {
+            session_id,
+            last_sequence: None,
+            nonces: HashSet::new(),
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Sequence number of the last envelope accepted, if any.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    /// Accepts the header of a received envelope, or rejects it as a replay.
+    ///
+    /// Sequence numbers must increase from one envelope to the next, and no nonce
+    /// may be seen twice. A rejected header leaves the guard unchanged.
+    pub fn check(&mut self, header: &Header) -> Result<(), DarkpoolError> {
+        if header.session_id != self.session_id {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "envelope belongs to session {:016x}, not {:016x}",
+                header.session_id, self.session_id
+            )));
+        }
+        if let Some(last) = self.last_sequence
+            && header.sequence <= last
+        {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "envelope {} received after envelope {}",
+                header.sequence, last
+            )));
+        }
+        if !self.nonces.insert(header.nonce) {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "nonce {:016x} was already used in this session",
+                header.nonce
+            )));
+        }
+        self.last_sequence = Some(header.sequence);
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tfhe::safe_serialization::safe_serialize;
+
+use super::codec::{self, Codec};
+use super::{Envelope, Header, PayloadType, SignedEnvelope, check_version, read_body};
+use crate::error::DarkpoolError;
+use crate::identity::{EnvelopeSignature, Identity, PeerId};
+use crate::limits::{SerializationLimits, check_size};
+
+/// JSON form of an `Envelope`, for transports that only carry text.
+///
+/// Header fields stay readable; the message is the same body as in the binary
+/// encoding, compressed or not according to `codec`, base64-encoded. The
+/// signature covers the binary header and body, so it verifies identically
+/// whichever encoding carried the envelope.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TextEnvelope {
+    pub version: u16,
+    pub session_id: u64,
+    pub payload_type: PayloadType,
+    pub codec: Codec,
+    pub sequence: u64,
+    pub nonce: u64,
+    pub message: String,
+    /// The signer's `PeerId`, base64-encoded.
+    pub signer: String,
+    /// The Ed25519 signature, base64-encoded.
+    pub signature: String,
+}
+
+impl Envelope {
+    /// Encodes and signs the envelope as a JSON document, failing if the message
+    /// is over its limit.
+    pub fn encode_text(
+        &self,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<String, DarkpoolError> {
+        Ok(serde_json::to_string(&self.to_text(identity, limits)?)?)
+    }
+
+    /// Signs the envelope into its JSON form, for embedding in another document.
+    pub fn to_text(
+        &self,
+        identity: &Identity,
+        limits: &SerializationLimits,
+    ) -> Result<TextEnvelope, DarkpoolError> {
+        let mut header = Vec::new();
+        safe_serialize(&self.header, &mut header, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        let body = codec::encode_body(self.header.codec, &self.message, limits)?;
+        let signature = identity.sign_envelope(&header, &Sha256::digest(&body).into());
+        Ok(TextEnvelope {
+            version: self.header.version,
+            session_id: self.header.session_id,
+            payload_type: self.header.payload_type,
+            codec: self.header.codec,
+            sequence: self.header.sequence,
+            nonce: self.header.nonce,
+            message: STANDARD.encode(body),
+            signer: STANDARD.encode(signature.signer.0),
+            signature: STANDARD.encode(signature.signature),
+        })
+    }
+
+    /// Decodes a JSON envelope produced by `encode_text` and verifies its signature.
+    ///
+    /// As with the binary encoding, the version is checked and the message size
+    /// bounded before the message is decoded.
+    pub fn decode_text(
+        text: &str,
+        limits: &SerializationLimits,
+    ) -> Result<SignedEnvelope, DarkpoolError> {
+        Self::from_text(serde_json::from_str(text)?, limits)
+    }
+
+    /// Decodes the JSON form of an envelope, as embedded by `to_text`, and
+    /// verifies its signature. The body must hold the message alone.
+    pub fn from_text(
+        text: TextEnvelope,
+        limits: &SerializationLimits,
+    ) -> Result<SignedEnvelope, DarkpoolError> {
+        let header = Header {
+            version: text.version,
+            session_id: text.session_id,
+            payload_type: text.payload_type,
+            codec: text.codec,
+            sequence: text.sequence,
+            nonce: text.nonce,
+        };
+        check_version(&header)?;
+
+        // Every 4 base64 characters decode to at most 3 bytes.
+        check_size(
+            format!("{:?}", header.payload_type),
+            text.message.len() / 4 * 3,
+            limits.for_payload(header.payload_type),
+        )?;
+        let body = STANDARD.decode(&text.message)?;
+        limits.check_payload(header.payload_type, body.len())?;
+
+        let signer = STANDARD.decode(&text.signer)?.try_into().map_err(|_| {
+            DarkpoolError::ProtocolViolation("signer is not a 32-byte key".to_string())
+        })?;
+        let signature = EnvelopeSignature {
+            signer: PeerId(signer),
+            signature: STANDARD.decode(&text.signature)?,
+        };
+        let mut header_bytes = Vec::new();
+        safe_serialize(&header, &mut header_bytes, limits.header)
+            .map_err(DarkpoolError::Serialization)?;
+        let payload_digest = Sha256::digest(&body).into();
+        signature.verify(&header_bytes, &payload_digest)?;
+
+        // The signature covers the whole body, so it must hold nothing but the message.
+        let mut reader = body.as_slice();
+        let envelope = read_body(header, &mut reader, limits)?;
+        if !reader.is_empty() {
+            return Err(DarkpoolError::ProtocolViolation(format!(
+                "{} bytes after the message in the body",
+                reader.len()
+            )));
+        }
+        Ok(SignedEnvelope {
+            envelope,
+            signature,
+            payload_digest,
+        })
+    }
+}
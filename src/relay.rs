@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+
+use crate::error::DarkpoolError;
+use crate::limits::{SerializationLimits, SizeLimitExceeded};
+use crate::protocol::frame::{Frame, FrameReader, FrameWriter};
+use crate::session::Role;
+use crate::transport::{FrameRead, FrameWrite};
+
+/// Sent by the relay as the very first byte of a connection, before any frame,
+/// to tell the trader which end of the session it is.
+const INITIATOR_BYTE: u8 = 1;
+const RESPONDER_BYTE: u8 = 2;
+
+/// Bytes of an initiator's frames the relay holds for a pair, unless set with
+/// `Relay::with_pair_budget`.
+///
+/// Until its responder connects an initiator only sends its hello, so this is
+/// ample for traders following the protocol.
+pub const DEFAULT_PAIR_BUDGET: u32 = 1 << 20;
+
+/// Reads the role the relay assigned, as sent by `Relay` on a fresh connection.
+pub(crate) async fn read_role(stream: &mut TcpStream) -> Result<Role, DarkpoolError> {
+    match stream.read_u8().await.map_err(DarkpoolError::Transport)? {
+        INITIATOR_BYTE => Ok(Role::Initiator),
+        RESPONDER_BYTE => Ok(Role::Responder),
+        byte => Err(DarkpoolError::ProtocolViolation(format!(
+            "relay assigned unknown role {:#04x}",
+            byte
+        ))),
+    }
+}
+
+/// Pairs up traders and forwards frames between them.
+///
+/// Traders are paired in arrival order: the first of a pair becomes the session
+/// initiator, and its frames are stored until a responder connects. From then on
+/// the relay forwards frames both ways. It checks framing and frame sizes, but
+/// never decodes an envelope and never holds a client key; envelopes are signed
+/// end to end, so it cannot alter them either.
+pub struct Relay {
+    listener: TcpListener,
+    limits: SerializationLimits,
+    pair_budget: u32,
+    failures: Option<mpsc::Sender<PairFailure>>,
+}
+
+/// A pair the relay gave up on, as reported to `Relay::with_failures`.
+#[derive(Debug)]
+pub struct PairFailure {
+    /// Address the pair's initiator connected from.
+    pub initiator: SocketAddr,
+    pub error: DarkpoolError,
+}
+
+impl Relay {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self, DarkpoolError> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)
+                .await
+                .map_err(DarkpoolError::Transport)?,
+            limits: SerializationLimits::default(),
+            pair_budget: DEFAULT_PAIR_BUDGET,
+            failures: None,
+        })
+    }
+
+    /// Bounds forwarded frames with `limits` instead of the defaults.
+    pub fn with_limits(mut self, limits: SerializationLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Holds at most `bytes` of each initiator's frames instead of
+    /// `DEFAULT_PAIR_BUDGET`.
+    ///
+    /// An initiator going over it before its responder connects is disconnected.
+    /// Once the pair is complete, the relay stops reading from the initiator
+    /// until the responder has caught up instead. A single frame larger than the
+    /// budget is held on its own.
+    pub fn with_pair_budget(mut self, bytes: u32) -> Self {
+        self.pair_budget = bytes;
+        self
+    }
+
+    /// Reports pairs that fail on `failures`. Reports are dropped while the
+    /// channel is full, so that a slow reader never holds up the relay.
+    pub fn with_failures(mut self, failures: mpsc::Sender<PairFailure>) -> Self {
+        self.failures = Some(failures);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DarkpoolError> {
+        self.listener.local_addr().map_err(DarkpoolError::Transport)
+    }
+
+    /// Accepts and pairs traders until accepting fails.
+    ///
+    /// Every pair is served on its own task; a failing pair is reported to the
+    /// channel set with `with_failures`, if any, and does not affect the others.
+    pub async fn run(self) -> Result<(), DarkpoolError> {
+        let mut waiting: VecDeque<oneshot::Sender<TcpStream>> = VecDeque::new();
+        loop {
+            let (stream, addr) = self
+                .listener
+                .accept()
+                .await
+                .map_err(DarkpoolError::Transport)?;
+
+            // Hand the trader to the longest-waiting initiator that is still connected.
+            let mut stream = Some(stream);
+            while let Some(initiator) = waiting.pop_front() {
+                match initiator.send(stream.take().expect("trader not handed over yet")) {
+                    Ok(()) => break,
+                    Err(returned) => stream = Some(returned),
+                }
+            }
+
+            if let Some(stream) = stream {
+                let (responder_tx, responder_rx) = oneshot::channel();
+                waiting.push_back(responder_tx);
+                let (limits, budget) = (self.limits, self.pair_budget);
+                let failures = self.failures.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = serve_pair(stream, responder_rx, limits, budget).await
+                        && let Some(failures) = failures
+                    {
+                        let _ = failures.try_send(PairFailure {
+                            initiator: addr,
+                            error,
+                        });
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Serves one pair, from the initiator connecting until both traders hang up.
+async fn serve_pair(
+    mut initiator: TcpStream,
+    responder: oneshot::Receiver<TcpStream>,
+    limits: SerializationLimits,
+    budget: u32,
+) -> Result<(), DarkpoolError> {
+    initiator
+        .write_u8(INITIATOR_BYTE)
+        .await
+        .map_err(DarkpoolError::Transport)?;
+    let (initiator_read, initiator_write) = initiator.into_split();
+
+    // The initiator's frames are queued here until the responder shows up.
+    // Every queued frame holds its size in permits of the pair's budget, which
+    // is what bounds the queue.
+    let budget = PairBudget {
+        bytes: budget,
+        permits: Arc::new(Semaphore::new(budget as usize)),
+        paired: Arc::new(AtomicBool::new(false)),
+    };
+    let (stored_tx, mut stored_rx) = mpsc::unbounded_channel();
+    let mut pump = tokio::spawn(store_frames(
+        FrameReader::new(initiator_read, limits),
+        stored_tx,
+        budget.clone(),
+    ));
+    let mut responder = tokio::select! {
+        responder = responder => match responder {
+            Ok(responder) => responder,
+            // The relay stopped before pairing.
+            Err(_) => return Ok(()),
+        },
+        // The initiator hung up, sent garbage or went over budget while waiting.
+        stored = &mut pump => return stored.map_err(|e| DarkpoolError::Io(e.into()))?,
+    };
+    budget.paired.store(true, Ordering::Release);
+    responder
+        .write_u8(RESPONDER_BYTE)
+        .await
+        .map_err(DarkpoolError::Transport)?;
+    let (responder_read, responder_write) = responder.into_split();
+
+    let to_responder = async {
+        let mut frames = FrameWriter::new(responder_write, limits);
+        while let Some((frame, _permit)) = stored_rx.recv().await {
+            frames.write_frame(&frame).await?;
+        }
+        shutdown(frames).await
+    };
+    let to_initiator = async {
+        let mut from = FrameReader::new(responder_read, limits);
+        let mut to = FrameWriter::new(initiator_write, limits);
+        while let Some(frame) = from.read_frame().await? {
+            to.write_frame(&frame).await?;
+        }
+        shutdown(to).await
+    };
+    let from_initiator = async { pump.await.map_err(|e| DarkpoolError::Io(e.into()))? };
+    tokio::try_join!(to_responder, to_initiator, from_initiator)?;
+    Ok(())
+}
+
+/// Bytes of a pair's frames the relay may hold, and whether its responder has
+/// connected yet.
+#[derive(Clone)]
+struct PairBudget {
+    bytes: u32,
+    permits: Arc<Semaphore>,
+    paired: Arc<AtomicBool>,
+}
+
+impl PairBudget {
+    /// Reserves room for `frame`, failing if the pair is still waiting for its
+    /// responder and has none left.
+    async fn reserve(&self, frame: &Frame) -> Result<OwnedSemaphorePermit, DarkpoolError> {
+        // Empty frames take room too, so the count of queued frames is bounded.
+        let size = u32::try_from(frame.data.len()).unwrap_or(u32::MAX);
+        let permits = size.clamp(1, self.bytes.max(1));
+        if self.paired.load(Ordering::Acquire) {
+            return Ok(self
+                .permits
+                .clone()
+                .acquire_many_owned(permits)
+                .await
+                .expect("the budget is never closed"));
+        }
+        self.permits
+            .clone()
+            .try_acquire_many_owned(permits)
+            .map_err(|_| {
+                let held = u64::from(self.bytes) - self.permits.available_permits() as u64;
+                DarkpoolError::SizeLimitExceeded(SizeLimitExceeded {
+                    what: "frames held for a missing responder".to_string(),
+                    size: held + u64::from(size),
+                    limit: u64::from(self.bytes),
+                })
+            })
+    }
+}
+
+/// Reads frames until the stream ends, queueing them for forwarding within the
+/// pair's budget.
+async fn store_frames(
+    mut frames: FrameReader<OwnedReadHalf>,
+    stored: mpsc::UnboundedSender<(Frame, OwnedSemaphorePermit)>,
+    budget: PairBudget,
+) -> Result<(), DarkpoolError> {
+    while let Some(frame) = frames.read_frame().await? {
+        let permit = budget.reserve(&frame).await?;
+        if stored.send((frame, permit)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Tells the peer behind `frames` that nothing more will be forwarded to it.
+async fn shutdown<W: AsyncWrite + Unpin>(frames: FrameWriter<W>) -> Result<(), DarkpoolError> {
+    match frames.into_inner().shutdown().await {
+        Ok(()) => Ok(()),
+        // The peer may already be gone once the session is over.
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        Err(e) => Err(DarkpoolError::Transport(e)),
+    }
+}
@@ -0,0 +1,557 @@
+pub mod notifications;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use tfhe::set_server_key;
+use tfhe::zk::CompactPkeCrs;
+use tokio::net::TcpListener;
+
+use crate::batch::{EncryptedOrders, ResultBatch};
+use crate::common::Orders;
+use crate::error::DarkpoolError;
+use crate::identity::{Identity, PeerId};
+use crate::limits::SerializationLimits;
+use crate::matching::match_orders;
+use crate::proofs::OrderFieldBounds;
+use crate::protocol::frame::max_frame_len;
+use crate::protocol::replay::{ReplayGuard, Sequencer};
+use crate::protocol::{
+    Envelope, ErrorMessage, KeyAnnouncement, Message, PayloadType, SignedEnvelope,
+};
+use notifications::Subscriber;
+
+/// Media type of envelopes in their binary encoding.
+pub const BINARY_ENVELOPE: &str = "application/octet-stream";
+/// Media type of envelopes in their JSON encoding, see `TextEnvelope`.
+pub const TEXT_ENVELOPE: &str = "application/json";
+
+/// HTTP front end of an operator matching traders' encrypted orders against its
+/// own plaintext book.
+///
+/// - `POST /v1/keys` registers the `KeyAnnouncement` in the body, answering
+///   201 with a `Registration`.
+/// - `POST /v1/batches` stores the `ProvenOrderBatch` in the body once its
+///   proofs verify within the service's bounds, answering 201 with an `Upload`.
+/// - `POST /v1/batches/{batch}/match` starts matching the batch, answering 202.
+/// - `GET /v1/batches/{batch}/results` answers with the `MatchResults`, or 202
+///   while matching is still running.
+/// - `GET /v1/notifications` opens a WebSocket pushing results to a trader as
+///   they are computed, see `notifications`.
+///
+/// Envelopes are read and written in the binary encoding, or in the JSON one
+/// when the `Content-Type`, respectively `Accept`, header is `TEXT_ENVELOPE`.
+/// Every envelope a trader uploads must be signed by the same identity it
+/// registered its keys with, and pass the replay guard of the session its key
+/// announcement opened; results come back in that session, signed by the
+/// service. Failures are reported as an `ErrorMessage` in JSON.
+///
+/// Batch ids are random, and results are encrypted under the owner's key, so
+/// triggering and downloading need no further authentication. Traders prove
+/// their orders against the CRS the service was set up with, which they obtain
+/// offline.
+#[derive(Clone)]
+pub struct Service {
+    shared: Arc<Shared>,
+}
+
+/// Returned when a trader registers its keys.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Registration {
+    /// The trader's `PeerId`, in hex.
+    pub peer: String,
+}
+
+/// Returned when a trader uploads an order batch.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Upload {
+    /// Id of the batch in later requests, in hex.
+    pub batch: String,
+}
+
+/// A batch `Service::add_flow` could not match against the new flow.
+#[derive(Debug)]
+pub struct BatchFailure {
+    /// Id of the batch, in hex.
+    pub batch: String,
+    pub error: DarkpoolError,
+}
+
+struct Shared {
+    identity: Identity,
+    book: RwLock<Orders>,
+    limits: SerializationLimits,
+    crs: CompactPkeCrs,
+    bounds: OrderFieldBounds,
+    registry: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    traders: HashMap<PeerId, Trader>,
+    batches: HashMap<u64, Batch>,
+}
+
+struct Trader {
+    keys: Arc<KeyAnnouncement>,
+    guard: ReplayGuard,
+    sequencer: Sequencer,
+    /// Open notification channels of the trader.
+    subscribers: Vec<Subscriber>,
+}
+
+struct Batch {
+    owner: PeerId,
+    /// The keys the batch was uploaded under, which results are computed with.
+    keys: Arc<KeyAnnouncement>,
+    /// The orders, expanded once their proofs verified.
+    orders: Arc<EncryptedOrders>,
+    state: MatchState,
+}
+
+enum MatchState {
+    Uploaded,
+    Running,
+    Matched(ResultBatch),
+    Failed(ApiError),
+}
+
+impl Service {
+    /// A service verifying uploaded batches against `crs`, within the default
+    /// bounds.
+    pub fn new(identity: Identity, book: Orders, crs: CompactPkeCrs) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                identity,
+                book: RwLock::new(book),
+                limits: SerializationLimits::default(),
+                crs,
+                bounds: OrderFieldBounds::default(),
+                registry: Mutex::new(Registry::default()),
+            }),
+        }
+    }
+
+    /// Bounds uploaded envelopes with `limits` instead of the defaults.
+    ///
+    /// Only possible before the service is cloned or routed.
+    pub fn with_limits(mut self, limits: SerializationLimits) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("limits are set before the service is shared")
+            .limits = limits;
+        self
+    }
+
+    /// Requires uploaded batches to be proven within `bounds` instead of the
+    /// defaults.
+    ///
+    /// Only possible before the service is cloned or routed.
+    pub fn with_bounds(mut self, bounds: OrderFieldBounds) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("bounds are set before the service is shared")
+            .bounds = bounds;
+        self
+    }
+
+    /// Identity signing the service's envelopes.
+    pub fn peer_id(&self) -> PeerId {
+        self.shared.identity.peer_id()
+    }
+
+    /// Routes of the API, for serving or for testing without a socket.
+    pub fn router(&self) -> Router {
+        let limits = &self.shared.limits;
+        Router::new()
+            .route(
+                "/v1/keys",
+                post(register_keys).layer(DefaultBodyLimit::max(body_limit(
+                    limits,
+                    PayloadType::KeyAnnouncement,
+                ))),
+            )
+            .route(
+                "/v1/batches",
+                post(upload_batch).layer(DefaultBodyLimit::max(body_limit(
+                    limits,
+                    PayloadType::ProvenOrderBatch,
+                ))),
+            )
+            .route("/v1/batches/{batch}/match", post(start_matching))
+            .route("/v1/batches/{batch}/results", get(fetch_results))
+            .route("/v1/notifications", get(notifications::subscribe))
+            .with_state(self.shared.clone())
+    }
+
+    /// Serves the API on `listener` until accepting fails.
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), DarkpoolError> {
+        axum::serve(listener, self.router())
+            .await
+            .map_err(DarkpoolError::Transport)
+    }
+
+    /// Adds counterparty orders to the book, and matches every uploaded batch
+    /// against them.
+    ///
+    /// Results are pushed to the owner of each batch as it is matched, if it has
+    /// a notification channel open; they are not kept otherwise, and
+    /// `GET /v1/batches/{batch}/results` still serves the last match requested
+    /// against the whole book.
+    ///
+    /// A batch failing to match does not hold up the others: every other batch
+    /// is still matched and pushed, and the failures are returned, one per batch.
+    pub async fn add_flow(&self, flow: Orders) -> Vec<BatchFailure> {
+        self.shared
+            .book
+            .write()
+            .expect("a request panicked holding the book")
+            .order
+            .extend(flow.order.iter().cloned());
+
+        let batches: Vec<_> = self
+            .shared
+            .registry()
+            .batches
+            .iter()
+            .map(|(&id, batch)| (id, batch.owner, batch.keys.clone(), batch.orders.clone()))
+            .collect();
+        let flow = Arc::new(flow);
+        let mut evaluations = Vec::new();
+        for (id, owner, keys, orders) in batches {
+            let flow = flow.clone();
+            let task = tokio::task::spawn_blocking(move || evaluate(&keys, &orders, &flow));
+            evaluations.push((id, owner, task));
+        }
+        let mut failures = Vec::new();
+        for (id, owner, task) in evaluations {
+            match task.await.map_err(|e| DarkpoolError::Io(e.into())) {
+                Ok(Ok(results)) => self.shared.notify(id, owner, results),
+                Ok(Err(error)) | Err(error) => failures.push(BatchFailure {
+                    batch: format!("{:016x}", id),
+                    error,
+                }),
+            }
+        }
+        failures
+    }
+}
+
+impl Shared {
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry
+            .lock()
+            .expect("a request panicked holding the registry")
+    }
+
+    fn book(&self) -> RwLockReadGuard<'_, Orders> {
+        self.book
+            .read()
+            .expect("a request panicked holding the book")
+    }
+}
+
+async fn register_keys(
+    State(shared): State<Arc<Shared>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let envelope = decode(&shared, &headers, body).await?;
+    let peer = envelope.signer();
+    let header = envelope.envelope.header;
+    let Message::KeyAnnouncement(keys) = envelope.envelope.message else {
+        return Err(unexpected(
+            PayloadType::KeyAnnouncement,
+            header.payload_type,
+        ));
+    };
+    let keys = tokio::task::spawn_blocking(move || keys.verify_fingerprint().map(|()| keys))
+        .await
+        .map_err(|e| DarkpoolError::Io(e.into()))??;
+
+    // A trader rotating its keys stays in the session it opened.
+    match shared.registry().traders.entry(peer) {
+        Entry::Occupied(mut entry) => {
+            let trader = entry.get_mut();
+            trader.guard.check(&header)?;
+            trader.keys = Arc::new(keys);
+        }
+        Entry::Vacant(entry) => {
+            let mut guard = ReplayGuard::new(header.session_id);
+            guard.check(&header)?;
+            entry.insert(Trader {
+                keys: Arc::new(keys),
+                guard,
+                sequencer: Sequencer::new(header.session_id),
+                subscribers: Vec::new(),
+            });
+        }
+    }
+    let registration = Registration {
+        peer: peer.to_hex(),
+    };
+    Ok((StatusCode::CREATED, Json(registration)).into_response())
+}
+
+async fn upload_batch(
+    State(shared): State<Arc<Shared>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let envelope = decode(&shared, &headers, body).await?;
+    let peer = envelope.signer();
+    let header = envelope.envelope.header;
+    let Message::ProvenOrderBatch(orders) = envelope.envelope.message else {
+        return Err(unexpected(
+            PayloadType::ProvenOrderBatch,
+            header.payload_type,
+        ));
+    };
+
+    let keys = {
+        let mut registry = shared.registry();
+        let trader = registry.traders.get_mut(&peer).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("{} has not registered any keys", peer),
+            )
+        })?;
+        trader.guard.check(&header)?;
+        trader.keys.clone()
+    };
+
+    // Nothing is stored unless every proof checks out.
+    let verifier = shared.clone();
+    let verified_keys = keys.clone();
+    let orders = tokio::task::spawn_blocking(move || {
+        set_server_key(verified_keys.server_key.clone());
+        orders.verify(
+            &verified_keys.batch_header(),
+            &verifier.bounds,
+            &verified_keys.public_key,
+            &verifier.crs,
+        )
+    })
+    .await
+    .map_err(|e| DarkpoolError::Io(e.into()))??;
+
+    let id = random();
+    shared.registry().batches.insert(
+        id,
+        Batch {
+            owner: peer,
+            keys,
+            orders: Arc::new(orders),
+            state: MatchState::Uploaded,
+        },
+    );
+    let upload = Upload {
+        batch: format!("{:016x}", id),
+    };
+    Ok((StatusCode::CREATED, Json(upload)).into_response())
+}
+
+/// Starts matching a batch against the book, unless it is already being matched.
+/// A batch matched before is matched again, against the book as it is now.
+async fn start_matching(
+    State(shared): State<Arc<Shared>>,
+    Path(batch): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let id = parse_batch_id(&batch)?;
+    let (owner, keys, orders) = {
+        let mut registry = shared.registry();
+        let batch = registry
+            .batches
+            .get_mut(&id)
+            .ok_or_else(|| unknown_batch(id))?;
+        if let MatchState::Running = batch.state {
+            return Ok(StatusCode::ACCEPTED);
+        }
+        batch.state = MatchState::Running;
+        (batch.owner, batch.keys.clone(), batch.orders.clone())
+    };
+
+    let book = shared.book().clone();
+    let shared = shared.clone();
+    tokio::task::spawn_blocking(move || {
+        let state = match evaluate(&keys, &orders, &book) {
+            Ok(results) => {
+                shared.notify(id, owner, results.clone());
+                MatchState::Matched(results)
+            }
+            Err(e) => MatchState::Failed(e.into()),
+        };
+        if let Some(batch) = shared.registry().batches.get_mut(&id) {
+            batch.state = state;
+        }
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn fetch_results(
+    State(shared): State<Arc<Shared>>,
+    Path(batch): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let id = parse_batch_id(&batch)?;
+    let envelope = {
+        let mut registry = shared.registry();
+        let Registry { traders, batches } = &mut *registry;
+        let batch = batches.get(&id).ok_or_else(|| unknown_batch(id))?;
+        let results = match &batch.state {
+            MatchState::Uploaded => {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!("batch {:016x} has not been matched", id),
+                ));
+            }
+            MatchState::Running => return Ok(StatusCode::ACCEPTED.into_response()),
+            MatchState::Failed(e) => return Err(e.clone()),
+            MatchState::Matched(results) => results.clone(),
+        };
+        traders
+            .get_mut(&batch.owner)
+            .expect("batches belong to registered traders")
+            .sequencer
+            .envelope(Message::MatchResults(results))
+    };
+    encode(&shared, &headers, envelope).await
+}
+
+/// Matches an uploaded batch against the book, with the keys it was uploaded under.
+fn evaluate(
+    keys: &KeyAnnouncement,
+    orders: &EncryptedOrders,
+    book: &Orders,
+) -> Result<ResultBatch, DarkpoolError> {
+    set_server_key(keys.server_key.clone());
+    ResultBatch::new(&match_orders(orders, book), &keys.batch_header())
+}
+
+/// Decodes an uploaded envelope on the blocking pool, in the encoding named by
+/// its `Content-Type`.
+async fn decode(
+    shared: &Shared,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<SignedEnvelope, DarkpoolError> {
+    let text = has_media_type(headers, header::CONTENT_TYPE, TEXT_ENVELOPE);
+    let limits = shared.limits;
+    tokio::task::spawn_blocking(move || {
+        if text {
+            let body = std::str::from_utf8(&body).map_err(|e| {
+                DarkpoolError::ProtocolViolation(format!("text envelope is not UTF-8: {}", e))
+            })?;
+            Envelope::decode_text(body, &limits)
+        } else {
+            Envelope::decode(&body, &limits)
+        }
+    })
+    .await
+    .map_err(|e| DarkpoolError::Io(e.into()))?
+}
+
+/// Signs and encodes an envelope on the blocking pool, in the encoding named by
+/// the request's `Accept` header.
+async fn encode(
+    shared: &Shared,
+    headers: &HeaderMap,
+    envelope: Envelope,
+) -> Result<Response, ApiError> {
+    let text = has_media_type(headers, header::ACCEPT, TEXT_ENVELOPE);
+    let identity = shared.identity.clone();
+    let limits = shared.limits;
+    let (media_type, body) = tokio::task::spawn_blocking(move || {
+        if text {
+            let body = envelope.encode_text(&identity, &limits)?;
+            Ok::<_, DarkpoolError>((TEXT_ENVELOPE, body.into_bytes()))
+        } else {
+            Ok((BINARY_ENVELOPE, envelope.encode(&identity, &limits)?))
+        }
+    })
+    .await
+    .map_err(|e| DarkpoolError::Io(e.into()))??;
+    Ok(([(header::CONTENT_TYPE, media_type)], body).into_response())
+}
+
+fn has_media_type(headers: &HeaderMap, name: header::HeaderName, media_type: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(media_type))
+}
+
+/// Largest request body accepted for `payload_type`: a frame's worth, doubled to
+/// leave room for base64 and JSON in the text encoding.
+fn body_limit(limits: &SerializationLimits, payload_type: PayloadType) -> usize {
+    usize::try_from(max_frame_len(limits, payload_type).saturating_mul(2)).unwrap_or(usize::MAX)
+}
+
+fn parse_batch_id(batch: &str) -> Result<u64, ApiError> {
+    u64::from_str_radix(batch, 16).map_err(|_| unknown_batch_name(batch))
+}
+
+fn unknown_batch(id: u64) -> ApiError {
+    unknown_batch_name(&format!("{:016x}", id))
+}
+
+fn unknown_batch_name(batch: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, format!("no batch {}", batch))
+}
+
+fn unexpected(expected: PayloadType, received: PayloadType) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        format!("expected a {:?}, but received a {:?}", expected, received),
+    )
+}
+
+/// A failed request: its status, and an `ErrorMessage` as JSON body.
+#[derive(Clone, Debug)]
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: String) -> Self {
+        Self { status, message }
+    }
+}
+
+impl From<DarkpoolError> for ApiError {
+    fn from(e: DarkpoolError) -> Self {
+        let status = match &e {
+            DarkpoolError::SizeLimitExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            DarkpoolError::VersionMismatch { .. }
+            | DarkpoolError::KeyMismatch(_)
+            | DarkpoolError::HandshakeMismatch { .. } => StatusCode::CONFLICT,
+            DarkpoolError::InvalidOrder(_) | DarkpoolError::ProtocolViolation(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            DarkpoolError::Transport(_)
+            | DarkpoolError::WrongPassphrase
+            | DarkpoolError::KeyStore(_)
+            | DarkpoolError::Io(_)
+            | DarkpoolError::Serialization(_)
+            | DarkpoolError::Fhe(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::new(status, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorMessage {
+            message: self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{Shared, body_limit};
+use crate::batch::ResultBatch;
+use crate::error::DarkpoolError;
+use crate::identity::PeerId;
+use crate::matching::{MatchPolicy, ResultMode};
+use crate::protocol::codec::Codec;
+use crate::protocol::text::TextEnvelope;
+use crate::protocol::{Envelope, ErrorMessage, Message, PROTOCOL_VERSION, PayloadType};
+use crate::session::handshake::Hello;
+
+/// Pushed to a trader when one of its batches has been matched, as a text
+/// message holding this in JSON if the trader subscribed with a text envelope.
+///
+/// Traders that subscribed with a binary envelope get a binary message instead:
+/// the batch id as 8 little-endian bytes, then the envelope in the binary encoding.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Notification {
+    /// Id of the batch, in hex.
+    pub batch: String,
+    /// The `MatchResults` envelope.
+    pub envelope: TextEnvelope,
+}
+
+/// Results queued for a trader's notification channels.
+pub(super) struct Push {
+    batch: u64,
+    envelope: Envelope,
+}
+
+/// Pushes queued for a notification channel before it is considered lagging and
+/// dropped.
+pub const SUBSCRIBER_BACKLOG: usize = 16;
+
+pub(super) type Subscriber = mpsc::Sender<Arc<Push>>;
+
+impl Shared {
+    /// Queues the results of `batch` on its owner's open notification channels.
+    ///
+    /// Channels with a full backlog are dropped rather than grown, and their
+    /// trader is told it fell behind.
+    pub(super) fn notify(&self, batch: u64, owner: PeerId, results: ResultBatch) {
+        let mut registry = self.registry();
+        let Some(trader) = registry.traders.get_mut(&owner) else {
+            return;
+        };
+        trader
+            .subscribers
+            .retain(|subscriber| !subscriber.is_closed());
+        if trader.subscribers.is_empty() {
+            return;
+        }
+        let push = Arc::new(Push {
+            batch,
+            envelope: trader.sequencer.envelope(Message::MatchResults(results)),
+        });
+        // Lagging channels are dropped, as are channels closed meanwhile.
+        trader
+            .subscribers
+            .retain(|subscriber| subscriber.try_send(push.clone()).is_ok());
+    }
+}
+
+/// Opens a notification channel on a WebSocket.
+///
+/// The trader sends a `Hello` envelope first, signed with its registered
+/// identity and in the session its key announcement opened, as a binary or a
+/// text message. The service answers with a `Hello` of its own once the channel
+/// is open, then pushes a `Notification` for every batch of the trader matched
+/// from then on, in the same kind of message. If the channel cannot be opened,
+/// or the trader falls more than `SUBSCRIBER_BACKLOG` notifications behind, an
+/// `ErrorMessage` in JSON is sent instead, and the channel closed.
+pub(super) async fn subscribe(State(shared): State<Arc<Shared>>, ws: WebSocketUpgrade) -> Response {
+    ws.max_message_size(body_limit(&shared.limits, PayloadType::Hello))
+        .on_upgrade(move |mut socket| async move {
+            if let Err(e) = push_notifications(&shared, &mut socket).await {
+                let error = ErrorMessage {
+                    message: e.to_string(),
+                };
+                let error = serde_json::to_string(&error).expect("an error message serializes");
+                // The trader may be gone already.
+                let _ = socket.send(WsMessage::Text(error.into())).await;
+            }
+        })
+}
+
+async fn push_notifications(shared: &Shared, socket: &mut WebSocket) -> Result<(), DarkpoolError> {
+    let limits = shared.limits;
+    let (text, envelope) = loop {
+        match socket.recv().await {
+            Some(Ok(WsMessage::Text(message))) => {
+                break (true, Envelope::decode_text(message.as_str(), &limits)?);
+            }
+            Some(Ok(WsMessage::Binary(message))) => {
+                break (false, Envelope::decode(&message, &limits)?);
+            }
+            Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_))) => {}
+            // The trader left before subscribing.
+            _ => return Ok(()),
+        }
+    };
+
+    let peer = envelope.signer();
+    let header = envelope.envelope.header;
+    let Message::Hello(hello) = envelope.envelope.message else {
+        return Err(DarkpoolError::ProtocolViolation(format!(
+            "expected a Hello, but received a {:?}",
+            header.payload_type
+        )));
+    };
+    if !hello.result_modes.contains(&ResultMode::FullMatrix) {
+        return Err(DarkpoolError::HandshakeMismatch {
+            setting: "result mode",
+            local: format!("{:?}", [ResultMode::FullMatrix]),
+            peer: format!("{:?}", hello.result_modes),
+        });
+    }
+
+    let (subscriber, mut pushes) = mpsc::channel(SUBSCRIBER_BACKLOG);
+    let reply = {
+        let mut registry = shared.registry();
+        let trader = registry.traders.get_mut(&peer).ok_or_else(|| {
+            DarkpoolError::ProtocolViolation(format!("{} has not registered any keys", peer))
+        })?;
+        trader.guard.check(&header)?;
+        trader.subscribers.push(subscriber);
+        trader.sequencer.envelope(Message::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            profile: trader.keys.config.profile,
+            policy: MatchPolicy::default(),
+            result_modes: vec![ResultMode::FullMatrix],
+            codecs: vec![Codec::None],
+            limits,
+            resume: None,
+            proofs: Some(shared.bounds),
+        }))
+    };
+    let reply = if text {
+        WsMessage::Text(reply.encode_text(&shared.identity, &limits)?.into())
+    } else {
+        WsMessage::Binary(reply.encode(&shared.identity, &limits)?.into())
+    };
+    socket.send(reply).await.map_err(ws_error)?;
+
+    loop {
+        tokio::select! {
+            push = pushes.recv() => {
+                // Only dropped by `notify`, for lagging behind.
+                let Some(push) = push else {
+                    return Err(DarkpoolError::Transport(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!(
+                            "fell more than {} notifications behind",
+                            SUBSCRIBER_BACKLOG
+                        ),
+                    )));
+                };
+                let message = encode_push(shared, push, text).await?;
+                socket.send(message).await.map_err(ws_error)?;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Close(_))) | None => return Ok(()),
+                Some(Err(e)) => return Err(ws_error(e)),
+                // Nothing else is expected from the trader; pings are answered by axum.
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Signs and encodes a push on the blocking pool, as a text or a binary message.
+async fn encode_push(
+    shared: &Shared,
+    push: Arc<Push>,
+    text: bool,
+) -> Result<WsMessage, DarkpoolError> {
+    let identity = shared.identity.clone();
+    let limits = shared.limits;
+    tokio::task::spawn_blocking(move || {
+        if text {
+            let notification = Notification {
+                batch: format!("{:016x}", push.batch),
+                envelope: push.envelope.to_text(&identity, &limits)?,
+            };
+            Ok(WsMessage::Text(
+                serde_json::to_string(&notification)?.into(),
+            ))
+        } else {
+            let mut message = push.batch.to_le_bytes().to_vec();
+            message.extend(push.envelope.encode(&identity, &limits)?);
+            Ok(WsMessage::Binary(message.into()))
+        }
+    })
+    .await
+    .map_err(|e| DarkpoolError::Io(e.into()))?
+}
+
+fn ws_error(e: axum::Error) -> DarkpoolError {
+    DarkpoolError::Transport(io::Error::other(e))
+}
@@ -0,0 +1,609 @@
+pub mod handshake;
+pub mod resume;
+
+use std::io;
+use std::time::Duration;
+
+use tfhe::prelude::*;
+use tfhe::zk::CompactPkeCrs;
+use tfhe::{FheBool, set_server_key};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinError;
+
+use crate::batch::{BatchHeader, EncryptedOrders, OrderBatch, ResultBatch};
+use crate::common::Orders;
+use crate::error::DarkpoolError;
+use crate::identity::{Identity, PeerId};
+use crate::keys::KeySet;
+use crate::limits::SerializationLimits;
+use crate::matching::{
+    MatchPolicy, ResultMode, any_match, first_match_indices, match_orders_with_policy,
+};
+use crate::proofs::{OrderFieldBounds, ProvenOrderBatch};
+use crate::protocol::codec::Codec;
+use crate::protocol::{
+    KeyAnnouncement, Message, PROTOCOL_VERSION, PayloadType, SignedEnvelope, new_session_id,
+};
+use crate::relay::read_role;
+use crate::transport::{FrameRead, FrameWrite, Transport};
+use handshake::{Agreement, Handshake, Hello};
+use resume::{
+    Evaluation, Inbound, Outbound, PhaseTimeouts, ResumePoint, RetryPolicy, SessionState,
+};
+
+/// Which end of the connection a party is.
+///
+/// The flow is symmetric; the initiator picks the session id, which the
+/// responder learns from the initiator's hello, and its preferences win the
+/// handshake.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// One party of a two-party matching session over TCP.
+///
+/// After a handshake settling the session's settings, each party announces its
+/// keys, sends its orders encrypted under its own key, evaluates the peer's
+/// orders against its own book and returns the encrypted results, then decrypts
+/// the results the peer computed for its orders. This is the flow of
+/// `test_match`, run in both directions at once.
+///
+/// A session outlives its connections: if one drops, running the session again
+/// over a new connection to the same peer resumes it, skipping whatever both
+/// parties already sent, received or computed.
+pub struct MatchSession {
+    keys: KeySet,
+    identity: Identity,
+    orders: Orders,
+    policy: MatchPolicy,
+    result_modes: Vec<ResultMode>,
+    codecs: Vec<Codec>,
+    limits: SerializationLimits,
+    timeouts: PhaseTimeouts,
+    proofs: Option<(CompactPkeCrs, OrderFieldBounds)>,
+    state: Option<SessionState>,
+}
+
+/// Decrypted results for our orders, in the result mode agreed on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Matches {
+    /// Whether each of our orders matches each of the peer's, laid out as by
+    /// `match_orders`: entry `i * peer_orders + j` is our order `i` against the
+    /// peer's order `j`.
+    Matrix(Vec<bool>),
+    /// Whether any of our orders matches any of the peer's.
+    Any(bool),
+    /// For each of our orders, the index of the first peer order it matches.
+    FirstMatch(Vec<Option<u32>>),
+}
+
+impl Matches {
+    /// Whether any of our orders matches, whatever the result mode.
+    pub fn any(&self) -> bool {
+        match self {
+            Matches::Matrix(matches) => matches.iter().any(|&m| m),
+            Matches::Any(any) => *any,
+            Matches::FirstMatch(indices) => indices.iter().any(Option::is_some),
+        }
+    }
+}
+
+/// What a party learns from a session.
+pub struct MatchOutcome {
+    pub session_id: u64,
+    /// Identity that signed every envelope received from the peer.
+    pub peer: PeerId,
+    pub agreement: Agreement,
+    pub matches: Matches,
+}
+
+impl MatchSession {
+    pub fn new(keys: KeySet, identity: Identity, orders: Orders) -> Self {
+        Self {
+            keys,
+            identity,
+            orders,
+            policy: MatchPolicy::default(),
+            result_modes: ResultMode::ALL.to_vec(),
+            codecs: Codec::SUPPORTED.to_vec(),
+            limits: SerializationLimits::default(),
+            timeouts: PhaseTimeouts::default(),
+            proofs: None,
+            state: None,
+        }
+    }
+
+    /// Matches orders with `policy`; the peer must use the same one.
+    pub fn with_policy(mut self, policy: MatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Offers only `modes`, most preferred first, instead of every result mode.
+    pub fn with_result_modes(mut self, modes: &[ResultMode]) -> Self {
+        self.result_modes = modes.to_vec();
+        self
+    }
+
+    /// Offers only `codec`, instead of every supported codec.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codecs = vec![codec];
+        self
+    }
+
+    /// Bounds incoming envelopes with `limits` instead of the defaults.
+    pub fn with_limits(mut self, limits: SerializationLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Bounds each phase with `timeouts` instead of the defaults.
+    pub fn with_timeouts(mut self, timeouts: PhaseTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Proves our orders within `bounds` against the shared `crs`, and only
+    /// evaluates peer orders whose proofs verify within the same bounds. The
+    /// peer must require the same bounds.
+    pub fn with_proofs(mut self, crs: CompactPkeCrs, bounds: OrderFieldBounds) -> Self {
+        self.proofs = Some((crs, bounds));
+        self
+    }
+
+    /// Bounds the phases of later connections with `timeouts`, e.g. to be more
+    /// patient when resuming.
+    pub fn set_timeouts(&mut self, timeouts: PhaseTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Id of the session the next connection resumes, if any.
+    pub fn session_id(&self) -> Option<u64> {
+        self.resume_point().map(|point| point.session_id)
+    }
+
+    /// Times the peer's orders were evaluated in the current session, over all
+    /// its connections. Resuming a session never evaluates them again.
+    pub fn evaluations(&self) -> u32 {
+        self.state
+            .as_ref()
+            .map_or(0, |state| state.inbound.evaluations)
+    }
+
+    /// The hello this party opens its next connection with.
+    pub fn hello(&self) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            profile: self.keys.config.profile,
+            policy: self.policy,
+            result_modes: self.result_modes.clone(),
+            codecs: self.codecs.clone(),
+            limits: self.limits,
+            resume: self.resume_point(),
+            proofs: self.proofs.as_ref().map(|(_, bounds)| *bounds),
+        }
+    }
+
+    fn resume_point(&self) -> Option<ResumePoint> {
+        self.state.as_ref().and_then(SessionState::resume_point)
+    }
+
+    /// Connects to a listening peer and runs the session as initiator.
+    pub async fn connect(
+        &mut self,
+        addr: impl ToSocketAddrs,
+    ) -> Result<MatchOutcome, DarkpoolError> {
+        let stream = within("handshake", self.timeouts.handshake, async {
+            TcpStream::connect(addr)
+                .await
+                .map_err(DarkpoolError::Transport)
+        })
+        .await?;
+        self.run(stream, Role::Initiator).await
+    }
+
+    /// Connects as initiator until the session completes, resuming it after
+    /// every transport failure, for at most `retry.attempts` connections.
+    pub async fn connect_with_retry(
+        &mut self,
+        addr: impl ToSocketAddrs + Clone,
+        retry: RetryPolicy,
+    ) -> Result<MatchOutcome, DarkpoolError> {
+        let mut attempt = 1;
+        loop {
+            match self.connect(addr.clone()).await {
+                Err(e) if e.is_retryable() && attempt < retry.attempts => {
+                    tokio::time::sleep(retry.backoff * attempt).await;
+                    attempt += 1;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    /// Connects to a `Relay` and runs the session with the role it assigns, against
+    /// whichever trader the relay pairs us with.
+    ///
+    /// The relay may pair us with another trader each time, so a relayed
+    /// connection always starts a new session.
+    pub async fn connect_relay(
+        &mut self,
+        addr: impl ToSocketAddrs,
+    ) -> Result<MatchOutcome, DarkpoolError> {
+        let mut stream = within("handshake", self.timeouts.handshake, async {
+            TcpStream::connect(addr)
+                .await
+                .map_err(DarkpoolError::Transport)
+        })
+        .await?;
+        let role = read_role(&mut stream).await?;
+        self.state = None;
+        self.run(stream, role).await
+    }
+
+    /// Accepts one connection on `listener` and runs the session as responder.
+    pub async fn accept(&mut self, listener: &TcpListener) -> Result<MatchOutcome, DarkpoolError> {
+        let (stream, _) = listener.accept().await.map_err(DarkpoolError::Transport)?;
+        self.run(stream, Role::Responder).await
+    }
+
+    /// Runs the session over an established connection, one envelope per frame,
+    /// be it a `TcpStream` or a `MemoryTransport` to a peer in the same process.
+    ///
+    /// Hellos are exchanged first, uncompressed and within our own limits; the
+    /// agreed codec and limits apply from the key announcement on. Sending and
+    /// receiving then run concurrently, so neither party blocks on a full socket
+    /// buffer while the other is still writing its key announcement.
+    ///
+    /// The initiator resumes the session it last completed a handshake in, if
+    /// any, and the responder whichever session the initiator asks for, provided
+    /// it is the one it knows. Each party then sends again whatever the peer
+    /// says it did not receive, and carries on from there.
+    pub async fn run(
+        &mut self,
+        transport: impl Transport,
+        role: Role,
+    ) -> Result<MatchOutcome, DarkpoolError> {
+        let timeouts = self.timeouts;
+        let (reader, writer) = transport.into_frames(self.limits);
+        let mut incoming = Incoming { frames: reader };
+
+        // Settle which session this connection belongs to before saying hello,
+        // as the hello tells the peer where to resume it.
+        let mut peer_hello = None;
+        match role {
+            Role::Initiator => {
+                if self.resume_point().is_none() {
+                    self.state = Some(SessionState::new(new_session_id()));
+                }
+            }
+            Role::Responder => {
+                let envelope = within("handshake", timeouts.handshake, incoming.next()).await?;
+                let Message::Hello(hello) = &envelope.envelope.message else {
+                    return Err(unexpected(PayloadType::Hello, &envelope.envelope.message));
+                };
+                let hello = hello.clone();
+                self.settle_resumption(envelope.envelope.header.session_id, hello.resume)?;
+                self.state_mut().inbound.accept(envelope)?;
+                peer_hello = Some(hello);
+            }
+        }
+
+        let mut handshake = Handshake::new(self.hello(), role);
+        let own_header = self.keys.batch_header();
+        let (keys, orders, proofs) = (&self.keys, &self.orders, &self.proofs);
+        let mut outgoing = Outgoing {
+            frames: writer,
+            identity: &self.identity,
+            codec: Codec::None,
+        };
+        let state = self.state.as_mut().expect("the session is settled");
+        let session_id = state.session_id;
+        let SessionState {
+            agreement: settled,
+            outbound,
+            inbound,
+            ..
+        } = state;
+
+        outgoing
+            .send(outbound, Message::Hello(handshake.hello()?))
+            .await?;
+        let peer_hello = match peer_hello {
+            Some(hello) => hello,
+            None => {
+                let message =
+                    within("handshake", timeouts.handshake, incoming.receive(inbound)).await?;
+                match message {
+                    Message::Hello(hello) => hello,
+                    message => return Err(unexpected(PayloadType::Hello, &message)),
+                }
+            }
+        };
+        let peer_received = peer_hello.resume.map_or(0, |point| point.received);
+        handshake.receive(peer_hello)?;
+        let agreement = handshake.agree()?;
+        if let Some(previous) = *settled
+            && previous != agreement
+        {
+            return Err(DarkpoolError::HandshakeMismatch {
+                setting: "resumed session settings",
+                local: format!("{:?}", previous),
+                peer: format!("{:?}", agreement),
+            });
+        }
+        *settled = Some(agreement);
+        incoming.frames.set_limits(agreement.limits);
+        outgoing.frames.set_limits(agreement.limits);
+        outgoing.codec = agreement.codec;
+        outbound.forget_unreceived(peer_received);
+
+        let send = async {
+            if !outbound.has_sent(PayloadType::KeyAnnouncement) {
+                let announcement = KeyAnnouncement::new(keys);
+                outgoing
+                    .send(outbound, Message::KeyAnnouncement(announcement))
+                    .await?;
+            }
+            if !outbound.has_sent(batch_payload(proofs)) {
+                let orders = orders.clone();
+                let public_key = keys.public_key.clone();
+                let proofs = proofs.clone();
+                let batch = tokio::task::spawn_blocking(move || match proofs {
+                    Some((crs, bounds)) => {
+                        ProvenOrderBatch::prove(&orders, &public_key, &crs, &own_header, &bounds)
+                            .map(Message::ProvenOrderBatch)
+                    }
+                    None => OrderBatch::encrypt(&orders, &public_key, &own_header)
+                        .map(Message::OrderBatch),
+                })
+                .await
+                .map_err(join_error)??;
+                outgoing.send(outbound, batch).await?;
+            }
+            Ok::<_, DarkpoolError>(())
+        };
+        let receive = async {
+            if inbound.evaluation.is_some() {
+                return Ok(());
+            }
+            if inbound.announcement.is_none() {
+                let announcement = match incoming.receive(inbound).await? {
+                    Message::KeyAnnouncement(announcement) => announcement,
+                    message => return Err(unexpected(PayloadType::KeyAnnouncement, &message)),
+                };
+                announcement.verify_fingerprint()?;
+                inbound.announcement = Some(announcement);
+            }
+
+            // With proofs agreed on, unproven batches are refused unevaluated.
+            let batch = incoming.receive(inbound).await?;
+            if batch.payload_type() != batch_payload(proofs) {
+                return Err(unexpected(batch_payload(proofs), &batch));
+            }
+            let announcement = inbound.announcement.take().expect("announcement received");
+            let book = orders.clone();
+            let proofs = proofs.clone();
+            inbound.evaluations += 1;
+            inbound.evaluation = Some(Evaluation::Running(tokio::task::spawn_blocking(
+                move || {
+                    let peer_header = announcement.batch_header();
+                    set_server_key(announcement.server_key);
+                    let encrypted = match (batch, &proofs) {
+                        (Message::OrderBatch(batch), None) => batch.expand(&peer_header)?,
+                        (Message::ProvenOrderBatch(batch), Some((crs, bounds))) => {
+                            batch.verify(&peer_header, bounds, &announcement.public_key, crs)?
+                        }
+                        (batch, _) => return Err(unexpected(batch_payload(&proofs), &batch)),
+                    };
+                    evaluate(&encrypted, &book, &agreement, &peer_header)
+                },
+            )));
+            Ok(())
+        };
+        within("exchange", timeouts.exchange, async {
+            tokio::try_join!(send, receive)
+        })
+        .await?;
+
+        within("evaluation", timeouts.evaluation, async {
+            if let Some(Evaluation::Running(task)) = &mut inbound.evaluation {
+                match task.await.map_err(join_error).and_then(|results| results) {
+                    Ok(results) => inbound.evaluation = Some(Evaluation::Done(results)),
+                    // Not retried: the peer will not send its batch again, so the
+                    // session cannot complete.
+                    Err(e) => {
+                        inbound.evaluation = None;
+                        return Err(e);
+                    }
+                }
+            }
+            let results = match &inbound.evaluation {
+                Some(Evaluation::Done(results))
+                    if !outbound.has_sent(PayloadType::MatchResults) =>
+                {
+                    Some(results.clone())
+                }
+                _ => None,
+            };
+
+            let send = async {
+                if let Some(results) = results {
+                    outgoing
+                        .send(outbound, Message::MatchResults(results))
+                        .await?;
+                }
+                Ok::<_, DarkpoolError>(())
+            };
+            let receive = async {
+                if inbound.results.is_none() {
+                    match incoming.receive(inbound).await? {
+                        Message::MatchResults(results) => inbound.results = Some(results),
+                        message => return Err(unexpected(PayloadType::MatchResults, &message)),
+                    }
+                }
+                Ok(())
+            };
+            tokio::try_join!(send, receive)
+        })
+        .await?;
+
+        let results = inbound.results.as_ref().expect("results received");
+        Ok(MatchOutcome {
+            session_id,
+            peer: inbound.peer.expect("a peer envelope was received"),
+            agreement,
+            matches: decrypt_matches(results, &agreement, &own_header, keys)?,
+        })
+    }
+
+    /// Picks the session a responder continues, given the initiator's hello:
+    /// a new one, or the one it asks to resume if that is the one we know.
+    fn settle_resumption(
+        &mut self,
+        session_id: u64,
+        resume: Option<ResumePoint>,
+    ) -> Result<(), DarkpoolError> {
+        let Some(resume) = resume else {
+            self.state = Some(SessionState::new(session_id));
+            return Ok(());
+        };
+        let known = self.session_id();
+        if known != Some(resume.session_id) {
+            return Err(DarkpoolError::HandshakeMismatch {
+                setting: "resumed session",
+                local: known.map_or("none".to_string(), |id| format!("{:016x}", id)),
+                peer: format!("{:016x}", resume.session_id),
+            });
+        }
+        Ok(())
+    }
+
+    fn state_mut(&mut self) -> &mut SessionState {
+        self.state.as_mut().expect("the session is settled")
+    }
+}
+
+/// Matches the peer's orders against our book and packs the results as agreed.
+fn evaluate(
+    encrypted: &EncryptedOrders,
+    book: &Orders,
+    agreement: &Agreement,
+    peer_header: &BatchHeader,
+) -> Result<ResultBatch, DarkpoolError> {
+    let matrix = match_orders_with_policy(encrypted, book, agreement.policy);
+    match agreement.result_mode {
+        ResultMode::FullMatrix => ResultBatch::new(&matrix, peer_header),
+        ResultMode::SingleBit => ResultBatch::new(&[any_match(&matrix)], peer_header),
+        ResultMode::Index => {
+            ResultBatch::from_indices(&first_match_indices(&matrix, book.order.len()), peer_header)
+        }
+    }
+}
+
+fn decrypt_matches(
+    results: &ResultBatch,
+    agreement: &Agreement,
+    own_header: &BatchHeader,
+    keys: &KeySet,
+) -> Result<Matches, DarkpoolError> {
+    let decrypt = |result: &FheBool| -> bool { result.decrypt(&keys.client_key) };
+    Ok(match agreement.result_mode {
+        ResultMode::FullMatrix => {
+            Matches::Matrix(results.results(own_header)?.iter().map(decrypt).collect())
+        }
+        ResultMode::SingleBit => Matches::Any(results.results(own_header)?.iter().any(decrypt)),
+        ResultMode::Index => Matches::FirstMatch(
+            results
+                .indices(own_header)?
+                .iter()
+                .map(|index| {
+                    let index: u32 = index.decrypt(&keys.client_key);
+                    index.checked_sub(1)
+                })
+                .collect(),
+        ),
+    })
+}
+
+/// Receiving half of a connection. Envelopes are checked against the session's
+/// `Inbound` state, whichever connection they arrive over.
+struct Incoming<R> {
+    frames: R,
+}
+
+impl<R: FrameRead> Incoming<R> {
+    async fn next(&mut self) -> Result<SignedEnvelope, DarkpoolError> {
+        self.frames.read_envelope().await?.ok_or_else(|| {
+            DarkpoolError::Transport(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "peer closed the connection mid-session",
+            ))
+        })
+    }
+
+    async fn receive(&mut self, inbound: &mut Inbound) -> Result<Message, DarkpoolError> {
+        inbound.accept(self.next().await?)
+    }
+}
+
+/// Sending half of a connection; every envelope is logged in the session's
+/// `Outbound` state.
+struct Outgoing<'a, W> {
+    frames: W,
+    identity: &'a Identity,
+    codec: Codec,
+}
+
+impl<W: FrameWrite> Outgoing<'_, W> {
+    async fn send(
+        &mut self,
+        outbound: &mut Outbound,
+        message: Message,
+    ) -> Result<(), DarkpoolError> {
+        let envelope = outbound.sequencer.envelope(message).with_codec(self.codec);
+        // Logged before writing: whether it arrived is for the peer to say.
+        outbound
+            .sent
+            .push((envelope.header.sequence, envelope.header.payload_type));
+        self.frames.write_envelope(envelope, self.identity).await
+    }
+}
+
+/// Runs one phase of a session, failing with a `TimedOut` transport error if it
+/// takes longer than `timeout`.
+async fn within<T>(
+    phase: &str,
+    timeout: Duration,
+    future: impl Future<Output = Result<T, DarkpoolError>>,
+) -> Result<T, DarkpoolError> {
+    tokio::time::timeout(timeout, future).await.map_err(|_| {
+        DarkpoolError::Transport(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} phase took longer than {:?}", phase, timeout),
+        ))
+    })?
+}
+
+/// Kind of order batch both parties send, given whether they prove their orders.
+fn batch_payload(proofs: &Option<(CompactPkeCrs, OrderFieldBounds)>) -> PayloadType {
+    match proofs {
+        Some(_) => PayloadType::ProvenOrderBatch,
+        None => PayloadType::OrderBatch,
+    }
+}
+
+fn unexpected(expected: PayloadType, message: &Message) -> DarkpoolError {
+    DarkpoolError::ProtocolViolation(format!(
+        "expected a {:?}, but the peer sent a {:?}",
+        expected,
+        message.payload_type()
+    ))
+}
+
+/// A blocking task of the session panicked or was cancelled.
+fn join_error(e: JoinError) -> DarkpoolError {
+    DarkpoolError::Io(e.into())
+}
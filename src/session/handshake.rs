@@ -0,0 +1,242 @@
+use std::convert::Infallible;
+
+use serde::{Deserialize, Serialize};
+use tfhe_versionable::{Upgrade, Version, Versionize, VersionsDispatch};
+
+use super::Role;
+use super::resume::ResumePoint;
+use crate::config::ParameterProfile;
+use crate::error::DarkpoolError;
+use crate::limits::SerializationLimits;
+use crate::matching::{MatchPolicy, ResultMode};
+use crate::proofs::OrderFieldBounds;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::protocol::codec::Codec;
+
+/// First message of a session, stating what its sender is able and willing to do.
+#[derive(Serialize, Deserialize, Versionize, Clone, PartialEq, Eq, Debug)]
+#[versionize(HelloVersions)]
+pub struct Hello {
+    pub version: u16,
+    /// Profile of the sender's keys; both parties must use the same one.
+    pub profile: ParameterProfile,
+    /// Rule the sender matches orders with; both parties must use the same one.
+    pub policy: MatchPolicy,
+    /// Result modes the sender accepts, most preferred first.
+    pub result_modes: Vec<ResultMode>,
+    /// Codecs the sender can read, most preferred first.
+    pub codecs: Vec<Codec>,
+    /// Largest messages the sender accepts.
+    pub limits: SerializationLimits,
+    /// Session the sender wants to continue, instead of starting a new one.
+    pub resume: Option<ResumePoint>,
+    /// Bounds the sender proves its orders within and requires proofs of from
+    /// the peer, if it trades proven batches; both parties must use the same.
+    pub proofs: Option<OrderFieldBounds>,
+}
+
+/// Hello of protocol version 5, which could not resume a session.
+#[derive(Serialize, Deserialize, Version, Clone)]
+pub struct HelloV0 {
+    pub version: u16,
+    pub profile: ParameterProfile,
+    pub policy: MatchPolicy,
+    pub result_modes: Vec<ResultMode>,
+    pub codecs: Vec<Codec>,
+    pub limits: SerializationLimits,
+}
+
+impl Upgrade<HelloV1> for HelloV0 {
+    type Error = Infallible;
+
+    fn upgrade(self) -> Result<HelloV1, Self::Error> {
+        Ok(HelloV1 {
+            version: self.version,
+            profile: self.profile,
+            policy: self.policy,
+            result_modes: self.result_modes,
+            codecs: self.codecs,
+            limits: self.limits,
+            resume: None,
+        })
+    }
+}
+
+/// Hello of protocol version 6, which always traded unproven batches.
+#[derive(Serialize, Deserialize, Version, Clone)]
+pub struct HelloV1 {
+    pub version: u16,
+    pub profile: ParameterProfile,
+    pub policy: MatchPolicy,
+    pub result_modes: Vec<ResultMode>,
+    pub codecs: Vec<Codec>,
+    pub limits: SerializationLimits,
+    pub resume: Option<ResumePoint>,
+}
+
+impl Upgrade<Hello> for HelloV1 {
+    type Error = Infallible;
+
+    fn upgrade(self) -> Result<Hello, Self::Error> {
+        Ok(Hello {
+            version: self.version,
+            profile: self.profile,
+            policy: self.policy,
+            result_modes: self.result_modes,
+            codecs: self.codecs,
+            limits: self.limits,
+            resume: self.resume,
+            proofs: None,
+        })
+    }
+}
+
+#[derive(VersionsDispatch)]
+pub enum HelloVersions {
+    V0(HelloV0),
+    V1(HelloV1),
+    V2(Hello),
+}
+
+/// Settings both parties use for the rest of a session.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Agreement {
+    pub profile: ParameterProfile,
+    pub policy: MatchPolicy,
+    pub result_mode: ResultMode,
+    pub codec: Codec,
+    /// The tighter of both parties' limits, applied in both directions.
+    pub limits: SerializationLimits,
+    /// Bounds both parties' order batches are proven within, if they trade
+    /// proven batches.
+    pub proofs: Option<OrderFieldBounds>,
+}
+
+enum State {
+    Start,
+    HelloSent,
+    HelloReceived(Hello),
+    Exchanged(Hello),
+    Agreed(Agreement),
+    Failed,
+}
+
+/// Exchange of `Hello` messages opening a session.
+///
+/// Each party sends its hello and receives the peer's, in either order, then
+/// both derive the same `Agreement` from the two. Where preferences differ, the
+/// initiator's win. Any mismatch fails the handshake on both ends with the same
+/// typed error, so neither party needs to be told why the other gave up.
+pub struct Handshake {
+    local: Hello,
+    role: Role,
+    state: State,
+}
+
+impl Handshake {
+    pub fn new(local: Hello, role: Role) -> Self {
+        Self {
+            local,
+            role,
+            state: State::Start,
+        }
+    }
+
+    /// Returns the hello to send to the peer.
+    pub fn hello(&mut self) -> Result<Hello, DarkpoolError> {
+        self.state = match std::mem::replace(&mut self.state, State::Failed) {
+            State::Start => State::HelloSent,
+            State::HelloReceived(peer) => State::Exchanged(peer),
+            _ => return Err(out_of_order("sent a second hello")),
+        };
+        Ok(self.local.clone())
+    }
+
+    /// Records the hello received from the peer.
+    pub fn receive(&mut self, peer: Hello) -> Result<(), DarkpoolError> {
+        self.state = match std::mem::replace(&mut self.state, State::Failed) {
+            State::Start => State::HelloReceived(peer),
+            State::HelloSent => State::Exchanged(peer),
+            _ => return Err(out_of_order("received a second hello")),
+        };
+        Ok(())
+    }
+
+    /// Settles the session settings, once both hellos have been exchanged.
+    pub fn agree(&mut self) -> Result<Agreement, DarkpoolError> {
+        let State::Exchanged(peer) = std::mem::replace(&mut self.state, State::Failed) else {
+            return Err(out_of_order("agreed before exchanging hellos"));
+        };
+        let agreement = self.negotiate(&peer)?;
+        self.state = State::Agreed(agreement);
+        Ok(agreement)
+    }
+
+    /// The settings agreed on, once the handshake has succeeded.
+    pub fn agreement(&self) -> Option<&Agreement> {
+        match &self.state {
+            State::Agreed(agreement) => Some(agreement),
+            _ => None,
+        }
+    }
+
+    fn negotiate(&self, peer: &Hello) -> Result<Agreement, DarkpoolError> {
+        let local = &self.local;
+        if peer.version != PROTOCOL_VERSION {
+            return Err(DarkpoolError::VersionMismatch {
+                peer: peer.version,
+                local: PROTOCOL_VERSION,
+            });
+        }
+        check_equal("parameter profile", &local.profile, &peer.profile)?;
+        check_equal("match policy", &local.policy, &peer.policy)?;
+        check_equal("order proofs", &local.proofs, &peer.proofs)?;
+
+        let (initiator, responder) = match self.role {
+            Role::Initiator => (local, peer),
+            Role::Responder => (peer, local),
+        };
+        let result_mode = initiator
+            .result_modes
+            .iter()
+            .copied()
+            .find(|mode| responder.result_modes.contains(mode))
+            .ok_or_else(|| mismatch("result mode", &local.result_modes, &peer.result_modes))?;
+
+        Ok(Agreement {
+            profile: local.profile,
+            policy: local.policy,
+            result_mode,
+            codec: Codec::negotiate(&initiator.codecs, &responder.codecs),
+            limits: local.limits.intersect(&peer.limits),
+            proofs: local.proofs,
+        })
+    }
+}
+
+fn check_equal<T: PartialEq + std::fmt::Debug>(
+    setting: &'static str,
+    local: &T,
+    peer: &T,
+) -> Result<(), DarkpoolError> {
+    if local != peer {
+        return Err(mismatch(setting, local, peer));
+    }
+    Ok(())
+}
+
+fn mismatch<T: std::fmt::Debug + ?Sized>(
+    setting: &'static str,
+    local: &T,
+    peer: &T,
+) -> DarkpoolError {
+    DarkpoolError::HandshakeMismatch {
+        setting,
+        local: format!("{:?}", local),
+        peer: format!("{:?}", peer),
+    }
+}
+
+fn out_of_order(what: &str) -> DarkpoolError {
+    DarkpoolError::ProtocolViolation(format!("handshake out of order: {}", what))
+}
@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tfhe_versionable::{Versionize, VersionsDispatch};
+use tokio::task::JoinHandle;
+
+use crate::batch::ResultBatch;
+use crate::error::DarkpoolError;
+use crate::identity::PeerId;
+use crate::protocol::replay::{ReplayGuard, Sequencer};
+use crate::protocol::{KeyAnnouncement, Message, PayloadType, SignedEnvelope};
+
+use super::handshake::Agreement;
+
+/// Where a party asks to pick up a session it already took part in.
+#[derive(Serialize, Deserialize, Versionize, Clone, Copy, PartialEq, Eq, Debug)]
+#[versionize(ResumePointVersions)]
+pub struct ResumePoint {
+    pub session_id: u64,
+    /// Sequence number of the next envelope the party expects from the peer;
+    /// everything before it has been received and need not be sent again.
+    pub received: u64,
+}
+
+#[derive(VersionsDispatch)]
+pub enum ResumePointVersions {
+    V0(ResumePoint),
+}
+
+/// How long each phase of a session may take before the attempt is given up.
+///
+/// An overrun fails the attempt with a `TimedOut` transport error, so it can be
+/// retried; work already done, such as a running evaluation, is kept.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PhaseTimeouts {
+    /// Connecting and exchanging hellos.
+    pub handshake: Duration,
+    /// Exchanging key announcements and order batches.
+    pub exchange: Duration,
+    /// Evaluating the peer's orders and receiving its results for ours.
+    pub evaluation: Duration,
+}
+
+impl Default for PhaseTimeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(30),
+            exchange: Duration::from_secs(10 * 60),
+            evaluation: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// How often, and how patiently, to reconnect after a transport failure.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// Connections tried in all, the first one included.
+    pub attempts: u32,
+    /// Pause before the first retry; each further retry waits one more `backoff`.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Everything one party has sent and received in a session, kept across
+/// connections so a session can continue where a dropped one stopped.
+pub(crate) struct SessionState {
+    pub(crate) session_id: u64,
+    /// Settled by the first handshake; later handshakes must settle the same.
+    pub(crate) agreement: Option<Agreement>,
+    pub(crate) outbound: Outbound,
+    pub(crate) inbound: Inbound,
+}
+
+impl SessionState {
+    pub(crate) fn new(session_id: u64) -> Self {
+        Self {
+            session_id,
+            agreement: None,
+            outbound: Outbound {
+                sequencer: Sequencer::new(session_id),
+                sent: Vec::new(),
+            },
+            inbound: Inbound {
+                guard: ReplayGuard::new(session_id),
+                peer: None,
+                announcement: None,
+                evaluation: None,
+                evaluations: 0,
+                results: None,
+            },
+        }
+    }
+
+    /// Where to resume from, once a handshake has succeeded; before that the
+    /// peer may not know the session at all, so it is started over instead.
+    pub(crate) fn resume_point(&self) -> Option<ResumePoint> {
+        self.agreement?;
+        Some(ResumePoint {
+            session_id: self.session_id,
+            received: self
+                .inbound
+                .guard
+                .last_sequence()
+                .map_or(0, |last| last + 1),
+        })
+    }
+}
+
+/// What a party sent, as the sequence number and type of every envelope.
+///
+/// Only the log is kept, not the envelopes: whatever the peer missed is sent
+/// again as a new envelope, since the peer's replay guard would reject the old
+/// one once it has seen the hello of the new connection.
+pub(crate) struct Outbound {
+    pub(crate) sequencer: Sequencer,
+    pub(crate) sent: Vec<(u64, PayloadType)>,
+}
+
+impl Outbound {
+    pub(crate) fn has_sent(&self, payload_type: PayloadType) -> bool {
+        self.sent.iter().any(|&(_, sent)| sent == payload_type)
+    }
+
+    /// Forgets the envelopes the peer did not receive, according to its resume
+    /// point, so they are sent again.
+    pub(crate) fn forget_unreceived(&mut self, received: u64) {
+        self.sent.retain(|&(sequence, _)| sequence < received);
+    }
+}
+
+/// What a party received, and what it made of it.
+pub(crate) struct Inbound {
+    /// Sees every envelope from the peer, whichever connection it came over.
+    pub(crate) guard: ReplayGuard,
+    /// Signer of the first envelope received; only it may continue the session.
+    pub(crate) peer: Option<PeerId>,
+    /// Held until the peer's order batch arrives.
+    pub(crate) announcement: Option<KeyAnnouncement>,
+    pub(crate) evaluation: Option<Evaluation>,
+    /// Times an evaluation of the peer's orders was started.
+    pub(crate) evaluations: u32,
+    /// Results the peer computed for our orders.
+    pub(crate) results: Option<ResultBatch>,
+}
+
+impl Inbound {
+    /// Checks a received envelope against the session and returns its message.
+    pub(crate) fn accept(&mut self, envelope: SignedEnvelope) -> Result<Message, DarkpoolError> {
+        match self.peer {
+            Some(peer) => envelope.check_signer(&peer)?,
+            None => self.peer = Some(envelope.signer()),
+        }
+        self.guard.check(&envelope.envelope.header)?;
+
+        match envelope.envelope.message {
+            Message::Abort(e) => Err(DarkpoolError::ProtocolViolation(format!(
+                "peer aborted the session: {}",
+                e.message
+            ))),
+            message => Ok(message),
+        }
+    }
+}
+
+/// Our evaluation of the peer's orders. It runs on the blocking pool, detached
+/// from any connection, so it carries on while the peer reconnects.
+pub(crate) enum Evaluation {
+    Running(JoinHandle<Result<ResultBatch, DarkpoolError>>),
+    Done(ResultBatch),
+}
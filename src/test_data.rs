@@ -330,7 +330,7 @@ pub fn user_two_orders_no_match() -> Orders {
 ///
 /// * `number_of_orders` - The number of orders to generate for each party.
 /// * `is_match` - If true, party B will include at least one order that can match party A's first order.
-///              A match is defined by: price equality and opposite side (i.e. one is buy and one is sell).
+///   A match is defined by: price equality and opposite side (i.e. one is buy and one is sell).
 ///
 /// # Returns
 ///
@@ -361,8 +361,8 @@ pub fn create_order_test_data(number_of_orders: u32, is_match: bool) -> (Orders,
             id: random::<u32>(),
             asset_a: 1,
             asset_b: 2,
-            price: orders_a.get(0).map_or(100, |o| o.price), // match party A's price
-            a_for_b: false,                                  // sell order
+            price: orders_a.first().map_or(100, |o| o.price), // match party A's price
+            a_for_b: false,                                   // sell order
         });
         // Generate the remaining orders as non-matching buy orders.
         for i in 1..number_of_orders {
@@ -0,0 +1,198 @@
+use std::io;
+
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+
+use crate::error::DarkpoolError;
+use crate::identity::Identity;
+use crate::limits::SerializationLimits;
+use crate::protocol::frame::{Frame, FrameReader, FrameWriter, check_frame_len};
+use crate::protocol::{Envelope, SignedEnvelope};
+
+/// Frames a `MemoryTransport` buffers in each direction before the sender waits
+/// for the receiver, as it would on a full socket buffer.
+const MEMORY_CAPACITY: usize = 8;
+
+/// A connection between two parties, carrying one envelope per frame.
+///
+/// Sessions only see the frames, so the same protocol runs over TCP and over a
+/// `MemoryTransport`, which keeps both parties in one process.
+pub trait Transport: Send {
+    type Reader: FrameRead;
+    type Writer: FrameWrite;
+
+    /// Splits the connection into its receiving and sending halves, both
+    /// bounding frames by `limits` until told otherwise.
+    fn into_frames(self, limits: SerializationLimits) -> (Self::Reader, Self::Writer);
+}
+
+/// Receiving half of a `Transport`.
+pub trait FrameRead: Send {
+    /// Reads the next frame, or `None` if the peer closed the connection cleanly
+    /// before it. Frames over the limits are rejected before being read.
+    fn read_frame(&mut self) -> impl Future<Output = Result<Option<Frame>, DarkpoolError>> + Send;
+
+    fn limits(&self) -> SerializationLimits;
+
+    /// Applies `limits` to the frames read from now on.
+    fn set_limits(&mut self, limits: SerializationLimits);
+
+    /// Reads and decodes the next envelope, or `None` if the peer closed the
+    /// connection cleanly before it. Decoding runs on the blocking pool.
+    fn read_envelope(
+        &mut self,
+    ) -> impl Future<Output = Result<Option<SignedEnvelope>, DarkpoolError>> + Send {
+        async move {
+            let Some(frame) = self.read_frame().await? else {
+                return Ok(None);
+            };
+            let limits = self.limits();
+            tokio::task::spawn_blocking(move || frame.decode(&limits))
+                .await
+                .map_err(|e| DarkpoolError::Io(e.into()))?
+                .map(Some)
+        }
+    }
+}
+
+/// Sending half of a `Transport`.
+pub trait FrameWrite: Send {
+    /// Writes `frame`, refusing frames the peer would reject as oversized.
+    fn write_frame(
+        &mut self,
+        frame: &Frame,
+    ) -> impl Future<Output = Result<(), DarkpoolError>> + Send;
+
+    fn limits(&self) -> SerializationLimits;
+
+    /// Applies `limits` to the frames written from now on.
+    fn set_limits(&mut self, limits: SerializationLimits);
+
+    /// Encodes and signs `envelope` on the blocking pool, then writes it as one frame.
+    fn write_envelope(
+        &mut self,
+        envelope: Envelope,
+        identity: &Identity,
+    ) -> impl Future<Output = Result<(), DarkpoolError>> + Send {
+        let identity = identity.clone();
+        async move {
+            let limits = self.limits();
+            let frame =
+                tokio::task::spawn_blocking(move || Frame::new(&envelope, &identity, &limits))
+                    .await
+                    .map_err(|e| DarkpoolError::Io(e.into()))??;
+            self.write_frame(&frame).await
+        }
+    }
+}
+
+impl Transport for TcpStream {
+    type Reader = FrameReader<OwnedReadHalf>;
+    type Writer = FrameWriter<OwnedWriteHalf>;
+
+    fn into_frames(self, limits: SerializationLimits) -> (Self::Reader, Self::Writer) {
+        let (reader, writer) = self.into_split();
+        (
+            FrameReader::new(reader, limits),
+            FrameWriter::new(writer, limits),
+        )
+    }
+}
+
+/// One end of an in-process connection, handing frames to the other end over
+/// tokio channels instead of a socket.
+///
+/// Frames are still encoded, signed and checked against the limits on both
+/// ends, so everything but the socket is exercised. Dropping an end closes the
+/// connection: the other end reads `None` once it has drained what was sent.
+pub struct MemoryTransport {
+    sender: mpsc::Sender<Frame>,
+    receiver: mpsc::Receiver<Frame>,
+}
+
+impl MemoryTransport {
+    /// Both ends of a new connection.
+    pub fn pair() -> (Self, Self) {
+        let (to_two, from_one) = mpsc::channel(MEMORY_CAPACITY);
+        let (to_one, from_two) = mpsc::channel(MEMORY_CAPACITY);
+        (
+            Self {
+                sender: to_two,
+                receiver: from_two,
+            },
+            Self {
+                sender: to_one,
+                receiver: from_one,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Reader = MemoryReader;
+    type Writer = MemoryWriter;
+
+    fn into_frames(self, limits: SerializationLimits) -> (Self::Reader, Self::Writer) {
+        (
+            MemoryReader {
+                frames: self.receiver,
+                limits,
+            },
+            MemoryWriter {
+                frames: self.sender,
+                limits,
+            },
+        )
+    }
+}
+
+/// Receiving half of a `MemoryTransport`.
+pub struct MemoryReader {
+    frames: mpsc::Receiver<Frame>,
+    limits: SerializationLimits,
+}
+
+impl FrameRead for MemoryReader {
+    async fn read_frame(&mut self) -> Result<Option<Frame>, DarkpoolError> {
+        let Some(frame) = self.frames.recv().await else {
+            return Ok(None);
+        };
+        check_frame_len(frame.payload_type, frame.data.len(), &self.limits)?;
+        Ok(Some(frame))
+    }
+
+    fn limits(&self) -> SerializationLimits {
+        self.limits
+    }
+
+    fn set_limits(&mut self, limits: SerializationLimits) {
+        self.limits = limits;
+    }
+}
+
+/// Sending half of a `MemoryTransport`.
+pub struct MemoryWriter {
+    frames: mpsc::Sender<Frame>,
+    limits: SerializationLimits,
+}
+
+impl FrameWrite for MemoryWriter {
+    async fn write_frame(&mut self, frame: &Frame) -> Result<(), DarkpoolError> {
+        check_frame_len(frame.payload_type, frame.data.len(), &self.limits)?;
+        self.frames.send(frame.clone()).await.map_err(|_| {
+            DarkpoolError::Transport(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "peer closed the connection",
+            ))
+        })
+    }
+
+    fn limits(&self) -> SerializationLimits {
+        self.limits
+    }
+
+    fn set_limits(&mut self, limits: SerializationLimits) {
+        self.limits = limits;
+    }
+}
@@ -0,0 +1,102 @@
+use tfhe::prelude::*;
+use tfhe::{FheBool, set_server_key};
+
+use fhe_darkpool_poc::batch::{
+    OrderBatch, deserialize_match_results, deserialize_order_batch, peek_batch_header,
+    serialize_match_results, serialize_order_batch,
+};
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
+/// Round-trips a batch of orders through a single compact ciphertext list.
+#[tokio::test]
+async fn test_order_batch_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(10, true);
+
+    // 1) Owner generates keys and packs all orders into one upload.
+    let keys = DarkpoolConfig::default().generate_keys()?;
+    let ser_batch = serialize_order_batch(&orders, &keys.public_key, &keys.batch_header())?;
+    println!(
+        "Serialized batch of {} orders: {} bytes",
+        orders.order.len(),
+        ser_batch.len()
+    );
+
+    // 2) Evaluator installs the owner's server key and expands the batch.
+    set_server_key(keys.server_key.clone());
+    let enc_orders = deserialize_order_batch(&ser_batch, &keys.batch_header())?;
+    assert_eq!(enc_orders.len(), orders.order.len());
+
+    // 3) Owner decrypts every field and compares with the plaintext orders.
+    for (i, order) in orders.order.iter().enumerate() {
+        let asset_a: u32 = enc_orders.asset_a[i].decrypt(&keys.client_key);
+        let asset_b: u32 = enc_orders.asset_b[i].decrypt(&keys.client_key);
+        let price: u32 = enc_orders.price[i].decrypt(&keys.client_key);
+        let side: bool = enc_orders.side[i].decrypt(&keys.client_key);
+        assert_eq!(asset_a, order.asset_a);
+        assert_eq!(asset_b, order.asset_b);
+        assert_eq!(price, order.price);
+        assert_eq!(side, order.a_for_b);
+    }
+
+    Ok(())
+}
+
+/// Batches made for one key must be rejected by a party holding another key.
+#[tokio::test]
+async fn test_batch_key_mismatch_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(2, false);
+
+    let keys_one = DarkpoolConfig::default().generate_keys()?;
+    let keys_two = DarkpoolConfig::default().generate_keys()?;
+    assert_ne!(keys_one.batch_header(), keys_two.batch_header());
+
+    // Orders encrypted by user_one, evaluated by someone holding user_two's key.
+    let ser_batch = serialize_order_batch(&orders, &keys_one.public_key, &keys_one.batch_header())?;
+    set_server_key(keys_two.server_key.clone());
+    assert!(matches!(
+        deserialize_order_batch(&ser_batch, &keys_two.batch_header()),
+        Err(DarkpoolError::KeyMismatch(_))
+    ));
+
+    // Results computed under user_one's key, delivered to user_two.
+    let results = vec![FheBool::encrypt(true, &keys_one.client_key)];
+    let ser_results = serialize_match_results(&results, &keys_one.batch_header())?;
+    assert!(matches!(
+        deserialize_match_results(&ser_results, &keys_two.batch_header()),
+        Err(DarkpoolError::KeyMismatch(_))
+    ));
+    assert_eq!(
+        deserialize_match_results(&ser_results, &keys_one.batch_header())?.len(),
+        1
+    );
+
+    Ok(())
+}
+
+/// Batches stored before they were versioned still load, and both batch types
+/// report their header.
+#[tokio::test]
+async fn test_legacy_batches_still_load() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(2, false);
+    let keys = DarkpoolConfig::default().generate_keys()?;
+    let header = keys.batch_header();
+
+    let batch = OrderBatch::encrypt(&orders, &keys.public_key, &header)?;
+    let legacy = bincode::serialize(&batch)?;
+    assert_ne!(
+        legacy,
+        serialize_order_batch(&orders, &keys.public_key, &header)?
+    );
+    assert_eq!(peek_batch_header(&legacy)?, header);
+    set_server_key(keys.server_key.clone());
+    assert_eq!(
+        deserialize_order_batch(&legacy, &header)?.len(),
+        orders.order.len()
+    );
+
+    let results = serialize_match_results(&[FheBool::encrypt(true, &keys.client_key)], &header)?;
+    assert_eq!(peek_batch_header(&results)?, header);
+    Ok(())
+}
@@ -41,20 +41,20 @@ async fn test_no_match() -> Result<(), Box<dyn std::error::Error>> {
     // Serialize them individually
     let ser_enc_asset_a_one: Vec<Vec<u8>> = enc_asset_a_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_asset_b_one: Vec<Vec<u8>> = enc_asset_b_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_price_one: Vec<Vec<u8>> = enc_price_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_side_one: Vec<Vec<u8>> = enc_side_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // Serialize user_one's server key
     let ser_server_key_one = bincode::serialize(&server_key_one)?;
@@ -65,20 +65,20 @@ async fn test_no_match() -> Result<(), Box<dyn std::error::Error>> {
     //
     let dec_enc_asset_a_one: Vec<FheUint32> = ser_enc_asset_a_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_asset_b_one: Vec<FheUint32> = ser_enc_asset_b_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_price_one: Vec<FheUint32> = ser_enc_price_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_side_one: Vec<FheBool> = ser_enc_side_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let server_key_one_for_two: ServerKey = bincode::deserialize(&ser_server_key_one)?;
     set_server_key(server_key_one_for_two);
@@ -104,16 +104,16 @@ async fn test_no_match() -> Result<(), Box<dyn std::error::Error>> {
     // Serialize user_two's match results
     let ser_match_two: Vec<Vec<u8>> = match_ciphertexts_two
         .iter()
-        .map(|m| safe_serialize_item(m).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     //
     // 4) user_one decrypts user_two's results.
     //
     let match_ciphertexts_for_one: Vec<FheBool> = ser_match_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let mut any_match_for_one = false;
     for mc in &match_ciphertexts_for_one {
@@ -150,40 +150,40 @@ async fn test_no_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_enc_asset_a_two: Vec<Vec<u8>> = enc_asset_a_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_asset_b_two: Vec<Vec<u8>> = enc_asset_b_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_price_two: Vec<Vec<u8>> = enc_price_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_side_two: Vec<Vec<u8>> = enc_side_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     let ser_server_key_two = bincode::serialize(&server_key_two)?;
 
     // user_one compares (decrypts user_two ciphertext, compares to user_one plaintext)
     let dec_enc_asset_a_two: Vec<FheUint32> = ser_enc_asset_a_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_asset_b_two: Vec<FheUint32> = ser_enc_asset_b_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_price_two: Vec<FheUint32> = ser_enc_price_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_side_two: Vec<FheBool> = ser_enc_side_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let server_key_two_for_one: ServerKey = bincode::deserialize(&ser_server_key_two)?;
     set_server_key(server_key_two_for_one);
@@ -206,14 +206,14 @@ async fn test_no_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_match_one: Vec<Vec<u8>> = match_ciphertexts_one
         .iter()
-        .map(|m| safe_serialize_item(m).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // user_two decrypts final match results
     let match_ciphertexts_for_two: Vec<FheBool> = ser_match_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let mut any_match_for_two = false;
     for mc in &match_ciphertexts_for_two {
@@ -264,12 +264,12 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_enc_price_one: Vec<Vec<u8>> = enc_price_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_side_one: Vec<Vec<u8>> = enc_side_one
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     let ser_server_key_one = bincode::serialize(&server_key_one)?;
 
@@ -279,12 +279,12 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
     //
     let dec_enc_price_one: Vec<FheUint32> = ser_enc_price_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_side_one: Vec<FheBool> = ser_enc_side_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let server_key_one_for_two: ServerKey = bincode::deserialize(&ser_server_key_one)?;
     set_server_key(server_key_one_for_two);
@@ -311,15 +311,15 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_match_two: Vec<Vec<u8>> = match_ciphertexts_two
         .iter()
-        .map(|m| safe_serialize_item(m).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // ---- TIMING for decrypting results ----
     let start_decrypt = Instant::now();
     let match_ciphertexts_for_one: Vec<FheBool> = ser_match_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let mut any_match_for_one = false;
     for mc in &match_ciphertexts_for_one {
@@ -362,12 +362,12 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_enc_price_two: Vec<Vec<u8>> = enc_price_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_side_two: Vec<Vec<u8>> = enc_side_two
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     let ser_server_key_two = bincode::serialize(&server_key_two)?;
 
@@ -375,12 +375,12 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
     // using only price and side.
     let dec_enc_price_two: Vec<FheUint32> = ser_enc_price_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_side_two: Vec<FheBool> = ser_enc_side_two
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let server_key_two_for_one: ServerKey = bincode::deserialize(&ser_server_key_two)?;
     set_server_key(server_key_two_for_one);
@@ -406,15 +406,15 @@ async fn test_match() -> Result<(), Box<dyn std::error::Error>> {
 
     let ser_match_one: Vec<Vec<u8>> = match_ciphertexts_one
         .iter()
-        .map(|m| safe_serialize_item(m).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // ---- TIMING for decryption (second round) ----
     let start_decrypt_two = Instant::now();
     let match_ciphertexts_for_two: Vec<FheBool> = ser_match_one
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let mut any_match_for_two = false;
     for mc in &match_ciphertexts_for_two {
@@ -461,12 +461,12 @@ async fn test_match_with_timing() -> Result<(), Box<dyn std::error::Error>> {
     // Serialize the encrypted price and side for party A
     let ser_enc_price_a: Vec<Vec<u8>> = enc_price_a
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
     let ser_enc_side_a: Vec<Vec<u8>> = enc_side_a
         .iter()
-        .map(|ct| safe_serialize_item(ct).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // Serialize Party A's server key so that Party B can use it.
     let ser_server_key_one = bincode::serialize(&server_key_one)?;
@@ -475,12 +475,12 @@ async fn test_match_with_timing() -> Result<(), Box<dyn std::error::Error>> {
     // For matching, we only consider the price and the side.
     let dec_enc_price_a: Vec<FheUint32> = ser_enc_price_a
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let dec_enc_side_a: Vec<FheBool> = ser_enc_side_a
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
 
     let server_key_one_for_two: ServerKey = bincode::deserialize(&ser_server_key_one)?;
     set_server_key(server_key_one_for_two);
@@ -491,7 +491,7 @@ async fn test_match_with_timing() -> Result<(), Box<dyn std::error::Error>> {
     let start = Instant::now();
     // For each order from Party A (encrypted) and each order from Party B (plaintext), do a match check.
     for (i, _) in orders_a.order.iter().enumerate() {
-        for (_, order_b) in orders_b.order.iter().enumerate() {
+        for order_b in orders_b.order.iter() {
             // Compare the price using homomorphic encryption.
             let eq_price = dec_enc_price_a[i].eq(order_b.price);
 
@@ -518,15 +518,15 @@ async fn test_match_with_timing() -> Result<(), Box<dyn std::error::Error>> {
     // Serialize the results.
     let ser_match_results: Vec<Vec<u8>> = match_results
         .iter()
-        .map(|m| safe_serialize_item(m).unwrap())
-        .collect();
+        .map(safe_serialize_item)
+        .collect::<Result<_, _>>()?;
 
     // ---- Time the decryption of results ----
     let start_decrypt = Instant::now();
     let dec_match_results: Vec<FheBool> = ser_match_results
         .iter()
-        .map(|bytes| safe_deserialize_item(bytes).unwrap())
-        .collect();
+        .map(|bytes| safe_deserialize_item(bytes))
+        .collect::<Result<_, _>>()?;
     let mut any_match_found = false;
     for result in &dec_match_results {
         if result.decrypt(&client_key_one) {
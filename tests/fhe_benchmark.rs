@@ -2,6 +2,15 @@ use std::time::Instant;
 use tfhe::prelude::*;
 use tfhe::{ConfigBuilder, FheUint32, generate_keys, set_server_key};
 
+use fhe_darkpool_poc::batch::{OrderBatch, ResultBatch};
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::matching::match_orders;
+use fhe_darkpool_poc::protocol::codec::Codec;
+use fhe_darkpool_poc::protocol::{Envelope, KeyAnnouncement, Message, new_session_id};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
 #[tokio::test]
 async fn test_fhe_vector_overlap_with_timing() -> Result<(), Box<dyn std::error::Error>> {
     // 1) Setup TFHE configuration and generate keys.
@@ -57,3 +66,54 @@ async fn test_fhe_vector_overlap_with_timing() -> Result<(), Box<dyn std::error:
 
     Ok(())
 }
+
+/// Reports bytes on the wire per message type, uncompressed and with zstd.
+#[tokio::test]
+async fn test_wire_size_with_compression() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let keys = DarkpoolConfig::default().generate_keys()?;
+    set_server_key(keys.server_key.clone());
+
+    let report = |label: &str, message: Message| -> Result<(), Box<dyn std::error::Error>> {
+        let envelope = Envelope::new(new_session_id(), message);
+        let plain = envelope.encode(&identity, &limits)?;
+        let envelope = envelope.with_codec(Codec::Zstd);
+        let compressed = envelope.encode(&identity, &limits)?;
+        println!(
+            "{:<28} {:>12} bytes -> {:>12} bytes with zstd ({:.1}%)",
+            label,
+            plain.len(),
+            compressed.len(),
+            100.0 * compressed.len() as f64 / plain.len() as f64
+        );
+        // Compression is transparent to the receiver.
+        let decoded = Envelope::decode(&compressed, &limits)?.envelope;
+        assert_eq!(decoded.header, envelope.header);
+        assert_eq!(
+            decoded.with_codec(Codec::None).encode(&identity, &limits)?,
+            plain
+        );
+        Ok(())
+    };
+
+    report(
+        "key announcement",
+        Message::KeyAnnouncement(KeyAnnouncement::new(&keys)),
+    )?;
+    for n in [2, 5, 10] {
+        let (orders_a, orders_b) = create_order_test_data(n, true);
+        let batch = OrderBatch::encrypt(&orders_a, &keys.public_key, &keys.batch_header())?;
+        let results = match_orders(&batch.expand(&keys.batch_header())?, &orders_b);
+
+        report(
+            &format!("order batch, {} orders", n),
+            Message::OrderBatch(batch),
+        )?;
+        report(
+            &format!("match results, {}x{}", n, n),
+            Message::MatchResults(ResultBatch::new(&results, &keys.batch_header())?),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,458 @@
+//! Wire format regression tests against the canonical envelopes in `tests/fixtures`.
+//!
+//! A failure here means this build no longer reads or writes what earlier builds
+//! did. If that is intended, bump `PROTOCOL_VERSION` and regenerate the fixtures:
+//!
+//! ```text
+//! cargo test --release --test golden_test -- --ignored regenerate_golden_fixtures
+//! ```
+//!
+//! Every fixture pins the protocol version it was written at, so a bump also
+//! means updating those versions below. The `_v<N>` fixtures hold what protocol
+//! version `N` wrote for types whose layout has changed since, and must keep
+//! decoding through their upgrades.
+
+use std::fs;
+use std::path::PathBuf;
+
+use bincode::Options;
+use serde::Serialize;
+use tfhe::named::Named;
+use tfhe::prelude::*;
+use tfhe::safe_serialization::safe_serialize;
+use tfhe::{ClientKey, CompactCiphertextList, set_server_key};
+use tfhe_versionable::{Version, Versionize, VersionsDispatch};
+
+use fhe_darkpool_poc::batch::{FIELDS_PER_ORDER, OrderBatch, ResultBatch};
+use fhe_darkpool_poc::common::{
+    Order, Orders, safe_deserialize_item_with_limit, safe_serialize_item_with_limit,
+};
+use fhe_darkpool_poc::config::{DarkpoolConfig, ParameterProfile};
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::matching::{MatchPolicy, ResultMode, match_orders};
+use fhe_darkpool_poc::protocol::codec::Codec;
+use fhe_darkpool_poc::protocol::{
+    Envelope, ErrorMessage, Header, HeaderV0, HeaderV1, HeaderVersions, Message, MessageVersions,
+    PROTOCOL_VERSION, PayloadType,
+};
+use fhe_darkpool_poc::session::handshake::{Hello, HelloV0, HelloV1, HelloVersions};
+
+const SESSION_ID: u64 = 0x0123_4567_89ab_cdef;
+const NONCE: u64 = 0xfedc_ba98_7654_3210;
+
+/// Signs every fixture, so the signatures are reproducible too.
+fn fixture_identity() -> Identity {
+    Identity::from_secret_bytes(&[0x42; 32])
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = fixture_path(name);
+    fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "cannot read fixture {}: {} (see the top of golden_test.rs to regenerate it)",
+            path.display(),
+            e
+        )
+    })
+}
+
+fn error_envelope() -> Envelope {
+    Envelope::new(
+        SESSION_ID,
+        Message::Abort(ErrorMessage {
+            message: "book closed".to_string(),
+        }),
+    )
+    .with_nonce(NONCE)
+}
+
+fn order(id: u32, asset_a: u32, asset_b: u32, price: u32, a_for_b: bool) -> Order {
+    Order {
+        id,
+        asset_a,
+        asset_b,
+        price,
+        a_for_b,
+    }
+}
+
+/// The orders in `order_batch.bin`; the first one crosses the book below.
+fn fixture_orders() -> Orders {
+    Orders {
+        order: vec![order(0, 1, 2, 100, true), order(1, 3, 4, 250, false)],
+    }
+}
+
+fn fixture_book() -> Orders {
+    Orders {
+        order: vec![order(0, 1, 2, 100, false), order(1, 5, 6, 70, true)],
+    }
+}
+
+/// Matches `fixture_orders` against `fixture_book`, one result per pair.
+const FIXTURE_MATCHES: [bool; 4] = [true, false, false, false];
+
+/// Writes `versioned`, an older version of `T`, as a build whose latest version
+/// of `T` it was did: tfhe's serialization header for `T`, then the versioned
+/// value. `current` only serves to lay out that header.
+fn legacy_serialize<T, V>(current: &T, versioned: &V) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+where
+    T: Serialize + Versionize + Named,
+    V: Serialize,
+{
+    let options = bincode::DefaultOptions::new().with_fixint_encoding();
+    let mut bytes = Vec::new();
+    safe_serialize(current, &mut bytes, u64::MAX)?;
+    let body = options.serialized_size(&current.versionize())? as usize;
+    bytes.truncate(bytes.len() - body);
+    options.serialize_into(&mut bytes, versioned)?;
+    Ok(bytes)
+}
+
+// What the derives generate for past versions, as the builds that wrote them saw it.
+type HeaderDispatch = <HeaderVersions as VersionsDispatch<Header>>::Owned;
+type HelloDispatch = <HelloVersions as VersionsDispatch<Hello>>::Owned;
+type MessageDispatch = <MessageVersions as VersionsDispatch<Message>>::Owned;
+type MessageVersion = <Message as Version>::Owned;
+
+fn current_hello() -> Hello {
+    Hello {
+        version: PROTOCOL_VERSION,
+        profile: ParameterProfile::Default,
+        policy: MatchPolicy::default(),
+        result_modes: vec![ResultMode::FullMatrix],
+        codecs: vec![Codec::Zstd, Codec::None],
+        limits: SerializationLimits::default(),
+        resume: None,
+        proofs: None,
+    }
+}
+
+fn legacy_hello(versioned: HelloDispatch) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    legacy_serialize(
+        &Message::Hello(current_hello()),
+        &MessageDispatch::V0(MessageVersion::Hello(versioned)),
+    )
+}
+
+/// Decodes a past header fixture, which receivers must reject by version
+/// rather than as garbage.
+fn decode_legacy_header(name: &str, version: u16) -> Result<Header, Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let bytes = fixture(name);
+    match Envelope::decode_header(&bytes, &limits) {
+        Err(DarkpoolError::VersionMismatch { peer, local }) => {
+            assert_eq!(peer, version);
+            assert_eq!(local, PROTOCOL_VERSION);
+        }
+        Err(e) => panic!("expected a version mismatch, got {}", e),
+        Ok(_) => panic!("expected a version mismatch"),
+    }
+    Ok(Header::decode(&bytes, &limits)?)
+}
+
+fn decode_legacy_hello(name: &str) -> Result<Hello, Box<dyn std::error::Error>> {
+    let bytes = fixture(name);
+    let limit = SerializationLimits::default().for_payload(PayloadType::Hello);
+    match safe_deserialize_item_with_limit(&bytes, limit)? {
+        Message::Hello(hello) => Ok(hello),
+        _ => panic!("expected a hello"),
+    }
+}
+
+fn assert_error_message(envelope: &Envelope) {
+    match &envelope.message {
+        Message::Abort(e) => assert_eq!(e.message, "book closed"),
+        _ => panic!("expected an error message"),
+    }
+}
+
+#[test]
+fn test_golden_error_envelope() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+    let bytes = fixture("error.bin");
+
+    let decoded = Envelope::decode(&bytes, &limits)?;
+    assert_eq!(decoded.signer(), identity.peer_id());
+    let header = decoded.envelope.header;
+    assert_eq!(header.version, 7);
+    assert_eq!(header.session_id, SESSION_ID);
+    assert_eq!(header.payload_type, PayloadType::Abort);
+    assert_eq!(header.codec, Codec::None);
+    assert_eq!(header.sequence, 0);
+    assert_eq!(header.nonce, NONCE);
+    assert_error_message(&decoded.envelope);
+
+    // Signatures are deterministic, so the whole envelope is reproducible.
+    assert_eq!(error_envelope().encode(&identity, &limits)?, bytes);
+    Ok(())
+}
+
+#[test]
+fn test_golden_compressed_envelope() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+
+    // zstd output may change across zstd releases, so only decoding is pinned.
+    let decoded = Envelope::decode(&fixture("error_zstd.bin"), &limits)?;
+    assert_eq!(decoded.signer(), identity.peer_id());
+    assert_eq!(decoded.envelope.header.version, 7);
+    assert_eq!(decoded.envelope.header.codec, Codec::Zstd);
+    assert_error_message(&decoded.envelope);
+    assert_eq!(
+        decoded
+            .envelope
+            .with_codec(Codec::None)
+            .encode(&identity, &limits)?,
+        fixture("error.bin")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_golden_text_envelope() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+    let text = String::from_utf8(fixture("error.json"))?;
+
+    let decoded = Envelope::decode_text(&text, &limits)?;
+    assert_eq!(decoded.signer(), identity.peer_id());
+    assert_eq!(decoded.envelope.header.version, 7);
+    assert_eq!(decoded.envelope.header, error_envelope().header);
+    assert_error_message(&decoded.envelope);
+    assert_eq!(error_envelope().encode_text(&identity, &limits)?, text);
+    Ok(())
+}
+
+#[test]
+fn test_golden_order_batch() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+    let bytes = fixture("order_batch.bin");
+
+    let decoded = Envelope::decode(&bytes, &limits)?;
+    assert_eq!(decoded.signer(), identity.peer_id());
+    assert_eq!(decoded.envelope.header.version, 7);
+    assert_eq!(
+        decoded.envelope.header.payload_type,
+        PayloadType::OrderBatch
+    );
+    let batch = match &decoded.envelope.message {
+        Message::OrderBatch(b) => b,
+        _ => panic!("expected an order batch"),
+    };
+    assert_eq!(batch.header.profile, ParameterProfile::Default);
+    assert_eq!(batch.header.epoch, 0);
+
+    // Expanding needs the server key, which is too large to keep as a fixture,
+    // so the ciphertexts are only checked for their layout.
+    let list: CompactCiphertextList =
+        safe_deserialize_item_with_limit(&batch.ciphertexts, batch.ciphertexts.len() as u64)?;
+    assert_eq!(list.len(), fixture_orders().order.len() * FIELDS_PER_ORDER);
+
+    assert_eq!(decoded.envelope.encode(&identity, &limits)?, bytes);
+    Ok(())
+}
+
+#[test]
+fn test_golden_match_results() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+    let bytes = fixture("match_results.bin");
+    let client_key_bytes = fixture("client_key.bin");
+    let client_key: ClientKey =
+        safe_deserialize_item_with_limit(&client_key_bytes, client_key_bytes.len() as u64)?;
+
+    let decoded = Envelope::decode(&bytes, &limits)?;
+    assert_eq!(decoded.signer(), identity.peer_id());
+    assert_eq!(decoded.envelope.header.version, 7);
+    assert_eq!(
+        decoded.envelope.header.payload_type,
+        PayloadType::MatchResults
+    );
+    let results = match &decoded.envelope.message {
+        Message::MatchResults(r) => r,
+        _ => panic!("expected match results"),
+    };
+    let matches: Vec<bool> = results
+        .results(&results.header)?
+        .iter()
+        .map(|r| r.decrypt(&client_key))
+        .collect();
+    assert_eq!(matches, FIXTURE_MATCHES);
+
+    assert_eq!(decoded.envelope.encode(&identity, &limits)?, bytes);
+    Ok(())
+}
+
+#[test]
+fn test_golden_header_v1() -> Result<(), Box<dyn std::error::Error>> {
+    let header = decode_legacy_header("header_v1.bin", 1)?;
+    assert_eq!(header.session_id, SESSION_ID);
+    assert_eq!(header.payload_type, PayloadType::Abort);
+    // Version 1 had no codecs, sequence numbers or nonces.
+    assert_eq!(header.codec, Codec::None);
+    assert_eq!(header.sequence, 0);
+    assert_eq!(header.nonce, 0);
+    Ok(())
+}
+
+#[test]
+fn test_golden_header_v3() -> Result<(), Box<dyn std::error::Error>> {
+    let header = decode_legacy_header("header_v3.bin", 3)?;
+    assert_eq!(header.session_id, SESSION_ID);
+    assert_eq!(header.payload_type, PayloadType::Abort);
+    assert_eq!(header.codec, Codec::Zstd);
+    assert_eq!(header.sequence, 0);
+    assert_eq!(header.nonce, 0);
+    Ok(())
+}
+
+#[test]
+fn test_golden_hello_v5() -> Result<(), Box<dyn std::error::Error>> {
+    let hello = decode_legacy_hello("hello_v5.bin")?;
+    assert_eq!(
+        hello,
+        Hello {
+            version: 5,
+            ..current_hello()
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_golden_hello_v6() -> Result<(), Box<dyn std::error::Error>> {
+    let hello = decode_legacy_hello("hello_v6.bin")?;
+    assert_eq!(
+        hello,
+        Hello {
+            version: 6,
+            ..current_hello()
+        }
+    );
+    Ok(())
+}
+
+/// Rewrites every fixture from the current build. Ignored: only run it when the
+/// wire format changes on purpose.
+#[test]
+#[ignore]
+fn regenerate_golden_fixtures() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = fixture_identity();
+    fs::create_dir_all(fixture_path(""))?;
+
+    fs::write(
+        fixture_path("error.bin"),
+        error_envelope().encode(&identity, &limits)?,
+    )?;
+    fs::write(
+        fixture_path("error_zstd.bin"),
+        error_envelope()
+            .with_codec(Codec::Zstd)
+            .encode(&identity, &limits)?,
+    )?;
+    fs::write(
+        fixture_path("error.json"),
+        error_envelope().encode_text(&identity, &limits)?,
+    )?;
+
+    // Past layouts, as the builds that wrote them did.
+    let header = error_envelope().header;
+    fs::write(
+        fixture_path("header_v1.bin"),
+        legacy_serialize(
+            &header,
+            &HeaderDispatch::V0(<HeaderV0 as Version>::Owned::from(HeaderV0 {
+                version: 1,
+                session_id: SESSION_ID,
+                payload_type: PayloadType::Abort,
+            })),
+        )?,
+    )?;
+    fs::write(
+        fixture_path("header_v3.bin"),
+        legacy_serialize(
+            &header,
+            &HeaderDispatch::V1(<HeaderV1 as Version>::Owned::from(HeaderV1 {
+                version: 3,
+                session_id: SESSION_ID,
+                payload_type: PayloadType::Abort,
+                codec: Codec::Zstd,
+            })),
+        )?,
+    )?;
+    let hello = current_hello();
+    fs::write(
+        fixture_path("hello_v5.bin"),
+        legacy_hello(HelloDispatch::V0(<HelloV0 as Version>::Owned::from(
+            HelloV0 {
+                version: 5,
+                profile: hello.profile,
+                policy: hello.policy,
+                result_modes: hello.result_modes.clone(),
+                codecs: hello.codecs.clone(),
+                limits: hello.limits,
+            },
+        )))?,
+    )?;
+    fs::write(
+        fixture_path("hello_v6.bin"),
+        legacy_hello(HelloDispatch::V1(<HelloV1 as Version>::Owned::from(
+            HelloV1 {
+                version: 6,
+                profile: hello.profile,
+                policy: hello.policy,
+                result_modes: hello.result_modes,
+                codecs: hello.codecs,
+                limits: hello.limits,
+                resume: None,
+            },
+        )))?,
+    )?;
+
+    let keys = DarkpoolConfig::default().generate_keys()?;
+    set_server_key(keys.server_key.clone());
+    fs::write(
+        fixture_path("client_key.bin"),
+        safe_serialize_item_with_limit(&keys.client_key, u64::MAX)?,
+    )?;
+
+    let header = keys.batch_header();
+    let batch = OrderBatch::encrypt(&fixture_orders(), &keys.public_key, &header)?;
+    let results = match_orders(&batch.expand(&header)?, &fixture_book());
+    assert_eq!(
+        results
+            .iter()
+            .map(|r| r.decrypt(&keys.client_key))
+            .collect::<Vec<bool>>(),
+        FIXTURE_MATCHES
+    );
+    fs::write(
+        fixture_path("order_batch.bin"),
+        Envelope::new(SESSION_ID, Message::OrderBatch(batch))
+            .with_nonce(NONCE)
+            .encode(&identity, &limits)?,
+    )?;
+    fs::write(
+        fixture_path("match_results.bin"),
+        Envelope::new(
+            SESSION_ID,
+            Message::MatchResults(ResultBatch::new(&results, &header)?),
+        )
+        .with_sequence(1)
+        .with_nonce(NONCE + 1)
+        .encode(&identity, &limits)?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use rand::random;
+use tfhe::prelude::*;
+use tfhe::{ClientKey, ConfigBuilder, FheBool, set_server_key};
+
+use fhe_darkpool_poc::batch::{
+    BatchHeader, deserialize_order_batch, peek_batch_header, serialize_match_results,
+    serialize_order_batch,
+};
+use fhe_darkpool_poc::config::{DarkpoolConfig, ParameterProfile};
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::keys::backup::{reconstruct_client_key, split_client_key};
+use fhe_darkpool_poc::keys::{KeyFingerprint, KeyRing, KeyStore};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
+/// Keys written to a store come back with the profile and epochs they were saved with.
+#[tokio::test]
+async fn test_key_store_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let config = DarkpoolConfig::new(ParameterProfile::LowFailureProbability);
+    let mut ring = KeyRing::new(config.generate_keys()?, KeyRing::DEFAULT_GRACE_PERIOD);
+    ring.rotate()?;
+
+    let store =
+        KeyStore::new(std::env::temp_dir().join(format!("darkpool-keys-{}", random::<u64>())));
+    store.save(&ring)?;
+
+    assert_eq!(store.load_config()?, config);
+    let loaded = store.load()?;
+    assert_eq!(loaded.epoch(), 1);
+    assert_eq!(loaded.current().config, config);
+    assert_eq!(
+        loaded.current().batch_header(),
+        ring.current().batch_header()
+    );
+    assert_eq!(loaded.retired().len(), 1);
+    assert_eq!(
+        loaded.keys_for_epoch(0).map(|k| k.fingerprint),
+        ring.keys_for_epoch(0).map(|k| k.fingerprint)
+    );
+
+    std::fs::remove_dir_all(store.root())?;
+    Ok(())
+}
+
+/// Sealed client keys only come back with the right passphrase.
+#[tokio::test]
+async fn test_sealed_key_store() -> Result<(), Box<dyn std::error::Error>> {
+    let ring = KeyRing::new(
+        DarkpoolConfig::default().generate_keys()?,
+        KeyRing::DEFAULT_GRACE_PERIOD,
+    );
+    let root = std::env::temp_dir().join(format!("darkpool-sealed-keys-{}", random::<u64>()));
+
+    KeyStore::new(&root)
+        .with_passphrase("correct horse battery staple")
+        .save(&ring)?;
+    assert!(!root.join("epoch-0").join("client_key.bin").exists());
+
+    // Right passphrase: the client key decrypts what the stored public key encrypted.
+    let loaded = KeyStore::new(&root)
+        .with_passphrase("correct horse battery staple")
+        .load()?;
+    let ct = FheBool::encrypt(true, &ring.current().client_key);
+    assert!(ct.decrypt(&loaded.current().client_key));
+
+    // Wrong passphrase: a distinct, matchable error.
+    let err = KeyStore::new(&root)
+        .with_passphrase("hunter2")
+        .load()
+        .err()
+        .unwrap();
+    assert!(matches!(err, DarkpoolError::WrongPassphrase));
+
+    // No passphrase at all.
+    assert!(KeyStore::new(&root).load().is_err());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+/// Any 3 of 5 backup shares rebuild the client key; fewer or tampered shares do not.
+#[test]
+fn test_client_key_backup() -> Result<(), Box<dyn std::error::Error>> {
+    let client_key = ClientKey::generate(ConfigBuilder::default().build());
+    let shares = split_client_key(&client_key, 3, 5)?;
+    assert_eq!(shares.len(), 5);
+
+    let subset = [shares[4].clone(), shares[0].clone(), shares[2].clone()];
+    let restored = reconstruct_client_key(&subset)?;
+    let ct = FheBool::encrypt(true, &client_key);
+    assert!(ct.decrypt(&restored));
+
+    assert!(reconstruct_client_key(&shares[..2]).is_err());
+
+    let mut tampered = subset.clone();
+    tampered[1].data[0] ^= 1;
+    assert!(reconstruct_client_key(&tampered).is_err());
+
+    Ok(())
+}
+
+/// Rotation keeps old results decryptable and moves resting orders to the new epoch.
+#[tokio::test]
+async fn test_key_rotation() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(3, false);
+    let mut ring = KeyRing::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Duration::from_secs(3600),
+    );
+
+    // 1) Orders and a pending result from epoch 0.
+    let old_header = ring.current().batch_header();
+    let resting = serialize_order_batch(&orders, &ring.current().public_key, &old_header)?;
+    let pending = serialize_match_results(
+        &[FheBool::encrypt(true, &ring.current().client_key)],
+        &old_header,
+    )?;
+
+    // 2) Rotate: epoch 0 is retired but still inside its grace period.
+    ring.rotate()?;
+    assert_eq!(ring.epoch(), 1);
+    assert_eq!(ring.decrypt_match_results(&pending)?, vec![true]);
+
+    // 3) Resting orders move to epoch 1 and decrypt to the same values.
+    let moved = ring.reencrypt_resting_orders(&resting)?;
+    let new_header = ring.current().batch_header();
+    assert_eq!(peek_batch_header(&moved)?, new_header);
+
+    set_server_key(ring.current().server_key.clone());
+    let decrypted =
+        deserialize_order_batch(&moved, &new_header)?.decrypt(&ring.current().client_key);
+    for (before, after) in orders.order.iter().zip(&decrypted.order) {
+        assert_eq!(before.price, after.price);
+        assert_eq!(before.a_for_b, after.a_for_b);
+    }
+
+    // 4) Once the grace period is over, epoch 0 results are rejected.
+    let mut expired_ring = KeyRing::new(DarkpoolConfig::default().generate_keys()?, Duration::ZERO);
+    let stale = serialize_match_results(
+        &[FheBool::encrypt(true, &expired_ring.current().client_key)],
+        &expired_ring.current().batch_header(),
+    )?;
+    expired_ring.rotate()?;
+    assert!(expired_ring.decrypt_match_results(&stale).is_err());
+    assert_eq!(expired_ring.prune_expired(), 1);
+
+    Ok(())
+}
+
+/// Peers on different parameter profiles must not accept each other's batches.
+#[test]
+fn test_profile_mismatch_is_rejected() {
+    let fingerprint = KeyFingerprint([7; 32]);
+    let ours = BatchHeader {
+        profile: ParameterProfile::Default,
+        epoch: 0,
+        key_fingerprint: fingerprint,
+    };
+    let theirs = BatchHeader {
+        profile: ParameterProfile::SmallInteger,
+        ..ours
+    };
+    let next_epoch = BatchHeader { epoch: 1, ..ours };
+    assert!(ours.check(&ours).is_ok());
+    assert!(theirs.check(&ours).is_err());
+    assert!(next_epoch.check(&ours).is_err());
+}
@@ -0,0 +1,133 @@
+use tfhe::prelude::*;
+use tfhe::zk::ZkComputeLoad;
+use tfhe::{ProvenCompactCiphertextList, set_server_key};
+
+use fhe_darkpool_poc::common::{Order, Orders, safe_serialize_item};
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::matching::{match_orders, verify_and_match};
+use fhe_darkpool_poc::proofs::{
+    FieldWidth, OrderFieldBounds, ProvenOrderBatch, generate_crs, serialize_proven_order_batch,
+};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
+/// A proven batch is verified and matched; a batch breaking the bounds is refused.
+#[tokio::test]
+async fn test_verify_and_match() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_a, orders_b) = create_order_test_data(4, true);
+
+    // 1) Setup: keys for user_one and a CRS shared by everyone.
+    let config = DarkpoolConfig::default();
+    let keys = config.generate_keys()?;
+    let crs = generate_crs(&config)?;
+    let bounds = OrderFieldBounds::default();
+
+    // 2) user_one proves and uploads its orders.
+    let ser_batch = serialize_proven_order_batch(
+        &orders_a,
+        &keys.public_key,
+        &crs,
+        &keys.batch_header(),
+        &bounds,
+    )?;
+
+    // 3) user_two verifies the proofs and matches against its plaintext book.
+    set_server_key(keys.server_key.clone());
+    let results = verify_and_match(
+        &ser_batch,
+        &keys.batch_header(),
+        &bounds,
+        &keys.public_key,
+        &crs,
+        &orders_b,
+    )?;
+    assert_eq!(results.len(), orders_a.order.len() * orders_b.order.len());
+
+    // 4) user_one decrypts: only its first order matches user_two's first order.
+    for (k, result) in results.iter().enumerate() {
+        let is_match: bool = result.decrypt(&keys.client_key);
+        assert_eq!(is_match, k == 0, "unexpected result at index {}", k);
+    }
+
+    // 5) An evaluator requiring narrower bounds rejects the batch before evaluating.
+    let narrow = OrderFieldBounds {
+        asset: FieldWidth::U8,
+        price: FieldWidth::U16,
+        max_price: 1000,
+    };
+    assert!(
+        verify_and_match(
+            &ser_batch,
+            &keys.batch_header(),
+            &narrow,
+            &keys.public_key,
+            &crs,
+            &orders_b,
+        )
+        .is_err()
+    );
+
+    // 6) Out-of-range values never get a proof in the first place.
+    let mut too_big = orders_a;
+    too_big.order[0].price = 1 << 20;
+    assert!(matches!(
+        serialize_proven_order_batch(
+            &too_big,
+            &keys.public_key,
+            &crs,
+            &keys.batch_header(),
+            &narrow,
+        ),
+        Err(DarkpoolError::InvalidOrder(_))
+    ));
+
+    Ok(())
+}
+
+/// Prices above the bound cannot be refused by the proofs, but never match, even
+/// when a dishonest prover skips the local check.
+#[tokio::test]
+async fn test_prices_above_the_bound_never_match() -> Result<(), Box<dyn std::error::Error>> {
+    let config = DarkpoolConfig::default();
+    let keys = config.generate_keys()?;
+    let crs = generate_crs(&config)?;
+    let header = keys.batch_header();
+    let bounds = OrderFieldBounds {
+        max_price: 400,
+        ..OrderFieldBounds::default()
+    };
+    let book = Orders {
+        order: vec![Order {
+            id: 0,
+            asset_a: 1,
+            asset_b: 2,
+            price: 500,
+            a_for_b: false,
+        }],
+    };
+
+    // The proof metadata binds the header and bounds, as `ProvenOrderBatch::prove` does.
+    let list = ProvenCompactCiphertextList::builder(&keys.public_key)
+        .push(1u16)
+        .push(2u16)
+        .push(500u32)
+        .push(true)
+        .build_with_proof_packed(
+            &crs,
+            &bincode::serialize(&(header, bounds))?,
+            ZkComputeLoad::Proof,
+        )?;
+    let batch = ProvenOrderBatch {
+        header,
+        bounds,
+        ciphertexts: safe_serialize_item(&list)?,
+    };
+
+    set_server_key(keys.server_key.clone());
+    let encrypted = batch.verify(&header, &bounds, &keys.public_key, &crs)?;
+    let results = match_orders(&encrypted, &book);
+    assert_eq!(results.len(), 1);
+    let is_match: bool = results[0].decrypt(&keys.client_key);
+    assert!(!is_match);
+    Ok(())
+}
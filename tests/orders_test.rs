@@ -0,0 +1,92 @@
+use serde::Serialize;
+use tfhe::set_server_key;
+
+use fhe_darkpool_poc::batch::{EncryptedOrders, OrderBatch};
+use fhe_darkpool_poc::common::{
+    Orders, deserialize_orders, safe_deserialize_item_with_limit, safe_serialize_item_with_limit,
+    serialize_orders,
+};
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
+/// `Order` as it was laid out before orders were versioned.
+#[derive(Serialize)]
+struct LegacyOrder {
+    id: u32,
+    asset_a: u32,
+    asset_b: u32,
+    price: u32,
+    a_for_b: bool,
+}
+
+#[derive(Serialize)]
+struct LegacyOrders {
+    order: Vec<LegacyOrder>,
+}
+
+fn fields(orders: &Orders) -> Vec<(u32, u32, u32, u32, bool)> {
+    orders
+        .order
+        .iter()
+        .map(|o| (o.id, o.asset_a, o.asset_b, o.price, o.a_for_b))
+        .collect()
+}
+
+#[test]
+fn test_orders_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(4, true);
+    let loaded = deserialize_orders(&serialize_orders(&orders)?)?;
+    assert_eq!(fields(&loaded), fields(&orders));
+    Ok(())
+}
+
+#[test]
+fn test_legacy_order_file_is_loaded() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(4, true);
+    let legacy = LegacyOrders {
+        order: orders
+            .order
+            .iter()
+            .map(|o| LegacyOrder {
+                id: o.id,
+                asset_a: o.asset_a,
+                asset_b: o.asset_b,
+                price: o.price,
+                a_for_b: o.a_for_b,
+            })
+            .collect(),
+    };
+    let data = bincode::serialize(&legacy)?;
+
+    let loaded = deserialize_orders(&data)?;
+    assert_eq!(fields(&loaded), fields(&orders));
+
+    // Anything else is still refused.
+    assert!(deserialize_orders(&data[..data.len() - 1]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_orders_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(2, false);
+    let keys = DarkpoolConfig::default().generate_keys()?;
+
+    let batch = OrderBatch::encrypt(&orders, &keys.public_key, &keys.batch_header())?;
+    set_server_key(keys.server_key.clone());
+    let encrypted = batch.expand(&keys.batch_header())?;
+
+    let data = safe_serialize_item_with_limit(&encrypted, u64::MAX)?;
+    let loaded: EncryptedOrders = safe_deserialize_item_with_limit(&data, data.len() as u64)?;
+    // Ids are never encrypted, so only the other fields survive.
+    let without_id = |orders: &Orders| -> Vec<_> {
+        fields(orders)
+            .into_iter()
+            .map(|(_, asset_a, asset_b, price, side)| (asset_a, asset_b, price, side))
+            .collect()
+    };
+    assert_eq!(
+        without_id(&loaded.decrypt(&keys.client_key)),
+        without_id(&orders)
+    );
+    Ok(())
+}
@@ -0,0 +1,545 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha2::{Digest, Sha256};
+use tfhe::prelude::*;
+use tfhe::safe_serialization::safe_serialize;
+use tfhe::set_server_key;
+
+use fhe_darkpool_poc::batch::{OrderBatch, ResultBatch};
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::matching::match_orders;
+use fhe_darkpool_poc::protocol::codec::Codec;
+use fhe_darkpool_poc::protocol::frame::{Frame, FrameReader, FrameWriter};
+use fhe_darkpool_poc::protocol::replay::{ReplayGuard, Sequencer};
+use fhe_darkpool_poc::protocol::text::TextEnvelope;
+use fhe_darkpool_poc::protocol::{
+    Envelope, ErrorMessage, KeyAnnouncement, Message, PROTOCOL_VERSION, PayloadType, new_session_id,
+};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+use fhe_darkpool_poc::transport::{FrameRead, FrameWrite};
+
+fn error_message() -> Message {
+    Message::Abort(ErrorMessage {
+        message: "book closed".to_string(),
+    })
+}
+
+fn error_envelope(session_id: u64) -> Envelope {
+    Envelope::new(session_id, error_message())
+}
+
+#[test]
+fn test_envelope_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let session_id = new_session_id();
+    let bytes = error_envelope(session_id).encode(&identity, &limits)?;
+
+    let header = Envelope::decode_header(&bytes, &limits)?;
+    assert_eq!(header.version, PROTOCOL_VERSION);
+    assert_eq!(header.session_id, session_id);
+    assert_eq!(header.payload_type, PayloadType::Abort);
+
+    match Envelope::decode(&bytes, &limits)?.envelope.message {
+        Message::Abort(e) => assert_eq!(e.message, "book closed"),
+        _ => panic!("expected an error message"),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_incompatible_version_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let mut envelope = error_envelope(new_session_id());
+    envelope.header.version = PROTOCOL_VERSION + 1;
+    let bytes = envelope.encode(&identity, &limits)?;
+
+    let err = Envelope::decode(&bytes, &limits).err().unwrap();
+    assert!(matches!(
+        err,
+        DarkpoolError::VersionMismatch { peer, local } if peer == local + 1
+    ));
+    assert!(Envelope::decode_header(&bytes, &limits).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_mislabelled_payload_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let mut envelope = error_envelope(new_session_id());
+    envelope.header.payload_type = PayloadType::OrderBatch;
+    let err = Envelope::decode(&envelope.encode(&identity, &limits)?, &limits)
+        .err()
+        .unwrap();
+    assert!(matches!(err, DarkpoolError::ProtocolViolation(_)));
+    Ok(())
+}
+
+#[test]
+fn test_oversized_message_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let bytes = error_envelope(new_session_id()).encode(&identity, &limits)?;
+
+    // A receiver with a tighter cap on error messages refuses the body before decoding it.
+    let strict = SerializationLimits { error: 8, ..limits };
+    let err = Envelope::decode(&bytes, &strict).err().unwrap();
+    let DarkpoolError::SizeLimitExceeded(err) = err else {
+        panic!("expected a size limit error, got {}", err);
+    };
+    assert_eq!(err.limit, 8);
+    assert!(err.size > 8);
+
+    // The same cap stops a sender from producing the message in the first place.
+    assert!(
+        error_envelope(new_session_id())
+            .encode(&identity, &strict)
+            .is_err()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_envelopes_stream_back_to_back() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let (first, second) = (new_session_id(), new_session_id());
+    let envelopes = [error_envelope(first), error_envelope(second)];
+
+    let mut stream = Vec::new();
+    for envelope in &envelopes {
+        envelope.write_to(&mut stream, &identity, &limits)?;
+    }
+    assert_eq!(
+        stream,
+        [
+            envelopes[0].encode(&identity, &limits)?,
+            envelopes[1].encode(&identity, &limits)?
+        ]
+        .concat()
+    );
+
+    let mut reader = stream.as_slice();
+    assert_eq!(
+        Envelope::read_from(&mut reader, &limits)?
+            .envelope
+            .header
+            .session_id,
+        first
+    );
+    assert_eq!(
+        Envelope::read_from(&mut reader, &limits)?
+            .envelope
+            .header
+            .session_id,
+        second
+    );
+    assert!(reader.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_truncation_is_classified_by_source() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let bytes = error_envelope(new_session_id()).encode(&identity, &limits)?;
+    let truncated = &bytes[..bytes.len() - 1];
+
+    // A stream that ends mid-message is a transport failure, worth retrying...
+    assert!(matches!(
+        Envelope::read_from(truncated, &limits),
+        Err(DarkpoolError::Transport(_))
+    ));
+    // ...while a complete buffer that does not decode is the peer's fault.
+    assert!(matches!(
+        Envelope::decode(truncated, &limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_envelopes_are_signed_by_their_sender() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let (alice, bob) = (Identity::generate(), Identity::generate());
+    let bytes = error_envelope(new_session_id()).encode(&alice, &limits)?;
+
+    let envelope = Envelope::decode(&bytes, &limits)?;
+    assert_eq!(envelope.signer(), alice.peer_id());
+    envelope.check_signer(&alice.peer_id())?;
+    assert!(matches!(
+        envelope.check_signer(&bob.peer_id()),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+
+    // The signature is deterministic and survives a round trip through text.
+    let text = Envelope::decode_text(&envelope.envelope.encode_text(&alice, &limits)?, &limits)?;
+    assert_eq!(text.signature, envelope.signature);
+    assert_eq!(text.payload_digest, envelope.payload_digest);
+    Ok(())
+}
+
+#[test]
+fn test_tampered_envelopes_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let session_id = new_session_id();
+    let bytes = error_envelope(session_id).encode(&identity, &limits)?;
+
+    // Flip a byte of the message text in the body.
+    let mut tampered = bytes.clone();
+    let at = tampered
+        .windows(4)
+        .position(|w| w == b"book")
+        .expect("message text in body");
+    tampered[at] ^= 0x20;
+    assert!(matches!(
+        Envelope::decode(&tampered, &limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+
+    // Move the envelope to another session, keeping the original body and signature.
+    let mut moved = bytes.clone();
+    let at = moved
+        .windows(8)
+        .position(|w| w == session_id.to_le_bytes())
+        .expect("session id in header");
+    moved[at..at + 8].copy_from_slice(&(session_id ^ 1).to_le_bytes());
+    assert_eq!(
+        Envelope::decode_header(&moved, &limits)?.session_id,
+        session_id ^ 1
+    );
+    assert!(matches!(
+        Envelope::decode(&moved, &limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_replayed_envelopes_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let mut sender = Sequencer::new(new_session_id());
+    let mut guard = ReplayGuard::new(sender.session_id());
+
+    let first = sender
+        .envelope(error_message())
+        .encode(&identity, &limits)?;
+    let second = sender
+        .envelope(error_message())
+        .encode(&identity, &limits)?;
+    for bytes in [&first, &second] {
+        guard.check(&Envelope::decode(bytes, &limits)?.envelope.header)?;
+    }
+
+    // Replaying an envelope, or anything from another session, is refused.
+    let replayed = Envelope::decode(&first, &limits)?.envelope.header;
+    assert!(matches!(
+        guard.check(&replayed),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+    let other_session = error_envelope(new_session_id()).with_sequence(2).header;
+    assert!(matches!(
+        guard.check(&other_session),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+
+    // So is a fresh sequence number carrying an already used nonce.
+    let reused_nonce = sender
+        .envelope(error_message())
+        .with_nonce(replayed.nonce)
+        .header;
+    assert!(guard.check(&reused_nonce).is_err());
+    guard.check(&sender.envelope(error_message()).header)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frames_round_trip_and_end_cleanly() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let (first, second) = (new_session_id(), new_session_id());
+
+    let mut writer = FrameWriter::new(Vec::new(), limits);
+    writer
+        .write_envelope(error_envelope(first), &identity)
+        .await?;
+    writer
+        .write_envelope(error_envelope(second).with_codec(Codec::Zstd), &identity)
+        .await?;
+    let stream = writer.into_inner();
+
+    let mut reader = FrameReader::new(stream.as_slice(), limits);
+    for session_id in [first, second] {
+        let envelope = reader.read_envelope().await?.expect("a frame");
+        assert_eq!(envelope.envelope.header.session_id, session_id);
+        assert_eq!(envelope.signer(), identity.peer_id());
+    }
+    assert!(reader.read_frame().await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_malformed_frames_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let frame = Frame::new(&error_envelope(new_session_id()), &identity, &limits)?;
+    let mut writer = FrameWriter::new(Vec::new(), limits);
+    writer.write_frame(&frame).await?;
+    let bytes = writer.into_inner();
+
+    // An unknown type byte.
+    let mut unknown = bytes.clone();
+    unknown[0] = 0xff;
+    assert!(matches!(
+        FrameReader::new(unknown.as_slice(), limits)
+            .read_frame()
+            .await,
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+
+    // A length over the limit for the type, rejected before anything else is read.
+    let mut oversized = bytes[..9].to_vec();
+    oversized[1..].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    assert!(matches!(
+        FrameReader::new(oversized.as_slice(), limits)
+            .read_frame()
+            .await,
+        Err(DarkpoolError::SizeLimitExceeded(_))
+    ));
+
+    // A stream ending mid-frame.
+    assert!(matches!(
+        FrameReader::new(&bytes[..bytes.len() - 1], limits)
+            .read_frame()
+            .await,
+        Err(DarkpoolError::Transport(_))
+    ));
+
+    // A frame whose type byte disagrees with the envelope it carries.
+    let mislabelled = Frame {
+        payload_type: PayloadType::OrderBatch,
+        ..frame
+    };
+    assert!(matches!(
+        mislabelled.decode(&limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_envelope_async_stream() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let session_id = new_session_id();
+    let (client, server) = tokio::io::duplex(64);
+
+    // The pipe is much smaller than the envelope, so both ends must make progress together.
+    let (written, read) = tokio::join!(
+        error_envelope(session_id).write_to_async(client, &identity, &limits),
+        Envelope::read_from_async(server, &limits),
+    );
+    written?;
+    let (envelope, _server) = read?;
+    assert_eq!(envelope.signer(), identity.peer_id());
+    let envelope = envelope.envelope;
+
+    assert_eq!(envelope.header.session_id, session_id);
+    assert_eq!(envelope.header.payload_type, PayloadType::Abort);
+    Ok(())
+}
+
+#[test]
+fn test_text_encoding_matches_binary() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let (orders, _) = create_order_test_data(3, true);
+    let keys = DarkpoolConfig::default().generate_keys()?;
+
+    let envelopes = [
+        error_envelope(new_session_id()),
+        Envelope::new(
+            new_session_id(),
+            Message::OrderBatch(OrderBatch::encrypt(
+                &orders,
+                &keys.public_key,
+                &keys.batch_header(),
+            )?),
+        ),
+    ];
+    for envelope in envelopes {
+        let text = envelope.encode_text(&identity, &limits)?;
+        assert!(text.contains(&serde_json::to_string(&envelope.header.payload_type)?));
+
+        let decoded = Envelope::decode_text(&text, &limits)?.envelope;
+        assert_eq!(decoded.header, envelope.header);
+        assert_eq!(
+            decoded.encode(&identity, &limits)?,
+            envelope.encode(&identity, &limits)?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_codec_negotiation() {
+    assert_eq!(
+        Codec::negotiate(Codec::SUPPORTED, Codec::SUPPORTED),
+        Codec::Zstd
+    );
+    assert_eq!(
+        Codec::negotiate(Codec::SUPPORTED, &[Codec::None]),
+        Codec::None
+    );
+    assert_eq!(
+        Codec::negotiate(&[Codec::None], Codec::SUPPORTED),
+        Codec::None
+    );
+    assert_eq!(Codec::negotiate(&[Codec::Zstd], &[]), Codec::None);
+}
+
+#[test]
+fn test_compressed_envelopes_stream_and_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let envelope = error_envelope(new_session_id()).with_codec(Codec::Zstd);
+    let bytes = envelope.encode(&identity, &limits)?;
+
+    let decoded = Envelope::decode(&bytes, &limits)?.envelope;
+    assert_eq!(decoded.header.codec, Codec::Zstd);
+    assert_eq!(decoded.encode(&identity, &limits)?, bytes);
+    let text = Envelope::decode_text(&envelope.encode_text(&identity, &limits)?, &limits)?.envelope;
+    assert_eq!(text.encode(&identity, &limits)?, bytes);
+
+    // A compressed envelope followed by a plain one: each read stops at its own end.
+    let mut stream = Vec::new();
+    envelope.write_to(&mut stream, &identity, &limits)?;
+    error_envelope(new_session_id()).write_to(&mut stream, &identity, &limits)?;
+    let mut reader = stream.as_slice();
+    assert_eq!(
+        Envelope::read_from(&mut reader, &limits)?
+            .envelope
+            .header
+            .codec,
+        Codec::Zstd
+    );
+    assert_eq!(
+        Envelope::read_from(&mut reader, &limits)?
+            .envelope
+            .header
+            .codec,
+        Codec::None
+    );
+    assert!(reader.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_corrupted_text_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let mut text: TextEnvelope =
+        serde_json::from_str(&error_envelope(new_session_id()).encode_text(&identity, &limits)?)?;
+    text.message.insert(0, '!');
+    assert!(Envelope::decode_text(&serde_json::to_string(&text)?, &limits).is_err());
+    Ok(())
+}
+
+/// Bytes after the message are rejected, even when signed, so every envelope
+/// has exactly one encoding.
+#[test]
+fn test_trailing_bytes_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let envelope = error_envelope(new_session_id());
+
+    let mut bytes = envelope.encode(&identity, &limits)?;
+    bytes.push(0);
+    assert!(matches!(
+        Envelope::decode(&bytes, &limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+
+    // Re-sign the padded body, so only the padding is wrong.
+    let mut text = envelope.to_text(&identity, &limits)?;
+    let mut body = STANDARD.decode(&text.message)?;
+    body.push(0);
+    let mut header = Vec::new();
+    safe_serialize(&envelope.header, &mut header, limits.header)?;
+    let signature = identity.sign_envelope(&header, &Sha256::digest(&body).into());
+    text.message = STANDARD.encode(&body);
+    text.signature = STANDARD.encode(&signature.signature);
+    assert!(matches!(
+        Envelope::from_text(text, &limits),
+        Err(DarkpoolError::ProtocolViolation(_))
+    ));
+    Ok(())
+}
+
+/// The one-directional `test_match` flow, with every hop going through an envelope.
+#[tokio::test]
+async fn test_match_over_envelopes() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = SerializationLimits::default();
+    let identity = Identity::generate();
+    let (orders_a, orders_b) = create_order_test_data(5, true);
+    let session_id = new_session_id();
+
+    // 1) user_one announces its keys and uploads its orders.
+    let keys = DarkpoolConfig::default().generate_keys()?;
+    let ser_announcement = Envelope::new(
+        session_id,
+        Message::KeyAnnouncement(KeyAnnouncement::new(&keys)),
+    )
+    .encode(&identity, &limits)?;
+    let ser_orders = Envelope::new(
+        session_id,
+        Message::OrderBatch(OrderBatch::encrypt(
+            &orders_a,
+            &keys.public_key,
+            &keys.batch_header(),
+        )?),
+    )
+    .encode(&identity, &limits)?;
+
+    // 2) user_two installs the announced key, evaluates and replies with results.
+    let announcement = match Envelope::decode(&ser_announcement, &limits)?
+        .envelope
+        .message
+    {
+        Message::KeyAnnouncement(a) => a,
+        _ => panic!("expected a key announcement"),
+    };
+    announcement.verify_fingerprint()?;
+    let peer_header = keys.batch_header();
+    set_server_key(announcement.server_key);
+
+    let batch = match Envelope::decode(&ser_orders, &limits)?.envelope.message {
+        Message::OrderBatch(b) => b,
+        _ => panic!("expected an order batch"),
+    };
+    let results = match_orders(&batch.expand(&peer_header)?, &orders_b);
+    let ser_results = Envelope::new(
+        session_id,
+        Message::MatchResults(ResultBatch::new(&results, &peer_header)?),
+    )
+    .encode(&identity, &limits)?;
+
+    // 3) user_one decrypts: its first order matches.
+    let envelope = Envelope::decode(&ser_results, &limits)?.envelope;
+    assert_eq!(envelope.header.session_id, session_id);
+    let results = match envelope.message {
+        Message::MatchResults(r) => r.results(&keys.batch_header())?,
+        _ => panic!("expected match results"),
+    };
+    let any_match = results.iter().any(|r| r.decrypt(&keys.client_key));
+    assert!(any_match, "Expected a match, found none");
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::protocol::frame::{Frame, FrameWriter};
+use fhe_darkpool_poc::protocol::replay::Sequencer;
+use fhe_darkpool_poc::protocol::{ErrorMessage, Message, new_session_id};
+use fhe_darkpool_poc::relay::Relay;
+use fhe_darkpool_poc::session::{MatchSession, Matches};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+use fhe_darkpool_poc::transport::FrameWrite;
+
+/// Four traders meet through one relay: they are paired up and each pair runs a
+/// full session, the relay only ever seeing framed, signed ciphertexts.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_traders_are_paired_through_relay() -> Result<(), Box<dyn std::error::Error>> {
+    let relay = Relay::bind("127.0.0.1:0").await?;
+    let addr = relay.local_addr()?;
+    tokio::spawn(relay.run());
+
+    let (orders_a, orders_b) = create_order_test_data(3, true);
+    // Keys are generated up front: a waiting initiator gives up on a responder
+    // that takes longer than the handshake timeout to show up.
+    let mut sessions = Vec::new();
+    for orders in [&orders_a, &orders_b, &orders_a, &orders_b] {
+        let identity = Identity::generate();
+        let peer_id = identity.peer_id();
+        let session = MatchSession::new(
+            DarkpoolConfig::default().generate_keys()?,
+            identity,
+            orders.clone(),
+        );
+        sessions.push((peer_id, session));
+    }
+    let mut traders = Vec::new();
+    for (peer_id, mut session) in sessions {
+        let trader = tokio::spawn(async move { session.connect_relay(addr).await });
+        traders.push((peer_id, trader));
+    }
+
+    let mut outcomes = HashMap::new();
+    for (peer_id, trader) in traders {
+        outcomes.insert(peer_id, trader.await??);
+    }
+    for (peer_id, outcome) in &outcomes {
+        // Pairings are mutual, and both ends agree on the session.
+        let peer = &outcomes[&outcome.peer];
+        assert_ne!(outcome.peer, *peer_id);
+        assert_eq!(peer.peer, *peer_id);
+        assert_eq!(peer.session_id, outcome.session_id);
+        let Matches::Matrix(matches) = &outcome.matches else {
+            panic!("expected the full matrix, got {:?}", outcome.matches);
+        };
+        assert_eq!(matches.len(), 9);
+    }
+    Ok(())
+}
+
+/// An initiator sending more than its pair's budget while no responder has
+/// connected is dropped, and the failure reported.
+#[tokio::test]
+async fn test_initiator_over_budget_is_dropped() -> Result<(), Box<dyn std::error::Error>> {
+    let (failures_tx, mut failures) = mpsc::channel(1);
+    let relay = Relay::bind("127.0.0.1:0")
+        .await?
+        .with_pair_budget(1024)
+        .with_failures(failures_tx);
+    let addr = relay.local_addr()?;
+    tokio::spawn(relay.run());
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let initiator = stream.local_addr()?;
+    assert_eq!(stream.read_u8().await?, 1, "expected the initiator role");
+    let identity = Identity::generate();
+    let mut sequencer = Sequencer::new(new_session_id());
+    let limits = SerializationLimits::default();
+    let mut frames = FrameWriter::new(stream, limits);
+    for _ in 0..2 {
+        let envelope = sequencer.envelope(Message::Abort(ErrorMessage {
+            message: "x".repeat(600),
+        }));
+        frames
+            .write_frame(&Frame::new(&envelope, &identity, &limits)?)
+            .await?;
+    }
+
+    let failure = tokio::time::timeout(Duration::from_secs(10), failures.recv())
+        .await?
+        .ok_or("the relay stopped")?;
+    assert_eq!(failure.initiator, initiator);
+    assert!(matches!(failure.error, DarkpoolError::SizeLimitExceeded(_)));
+    Ok(())
+}
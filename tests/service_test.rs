@@ -0,0 +1,349 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::http::{Method, Request, StatusCode, header};
+use futures_util::{SinkExt, StreamExt};
+use tfhe::prelude::*;
+use tfhe::zk::CompactPkeCrs;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tower::ServiceExt;
+
+use fhe_darkpool_poc::batch::OrderBatch;
+use fhe_darkpool_poc::common::Orders;
+use fhe_darkpool_poc::config::DarkpoolConfig;
+use fhe_darkpool_poc::identity::{Identity, PeerId};
+use fhe_darkpool_poc::keys::KeySet;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::matching::{MatchPolicy, ResultMode};
+use fhe_darkpool_poc::proofs::{OrderFieldBounds, ProvenOrderBatch, generate_crs};
+use fhe_darkpool_poc::protocol::codec::Codec;
+use fhe_darkpool_poc::protocol::replay::Sequencer;
+use fhe_darkpool_poc::protocol::{
+    Envelope, KeyAnnouncement, Message, PROTOCOL_VERSION, SignedEnvelope, new_session_id,
+};
+use fhe_darkpool_poc::service::notifications::Notification;
+use fhe_darkpool_poc::service::{BINARY_ENVELOPE, Registration, Service, TEXT_ENVELOPE, Upload};
+use fhe_darkpool_poc::session::handshake::Hello;
+use fhe_darkpool_poc::test_data::create_order_test_data;
+
+type Subscription = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Next data message on a notification channel.
+async fn next_message(socket: &mut Subscription) -> Result<WsMessage, Box<dyn std::error::Error>> {
+    loop {
+        let message = socket.next().await.ok_or("notification channel closed")??;
+        if message.is_text() || message.is_binary() {
+            return Ok(message);
+        }
+    }
+}
+
+/// A trader talking to the service, in the binary or the JSON encoding.
+struct Trader {
+    keys: KeySet,
+    crs: CompactPkeCrs,
+    identity: Identity,
+    sequencer: Sequencer,
+    media_type: &'static str,
+}
+
+impl Trader {
+    fn new(
+        media_type: &'static str,
+        crs: &CompactPkeCrs,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            keys: DarkpoolConfig::default().generate_keys()?,
+            crs: crs.clone(),
+            identity: Identity::generate(),
+            sequencer: Sequencer::new(new_session_id()),
+            media_type,
+        })
+    }
+
+    fn envelope(&mut self, message: Message) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let envelope = self.sequencer.envelope(message);
+        let limits = SerializationLimits::default();
+        Ok(if self.media_type == TEXT_ENVELOPE {
+            envelope.encode_text(&self.identity, &limits)?.into_bytes()
+        } else {
+            envelope.encode(&self.identity, &limits)?
+        })
+    }
+
+    fn announcement(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.envelope(Message::KeyAnnouncement(KeyAnnouncement::new(&self.keys)))
+    }
+
+    fn batch(&mut self, orders: &Orders) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let batch = ProvenOrderBatch::prove(
+            orders,
+            &self.keys.public_key,
+            &self.crs,
+            &self.keys.batch_header(),
+            &OrderFieldBounds::default(),
+        )?;
+        self.envelope(Message::ProvenOrderBatch(batch))
+    }
+
+    fn unproven_batch(&mut self, orders: &Orders) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let batch = OrderBatch::encrypt(orders, &self.keys.public_key, &self.keys.batch_header())?;
+        self.envelope(Message::OrderBatch(batch))
+    }
+
+    async fn request(
+        &self,
+        router: &Router,
+        method: Method,
+        uri: &str,
+        body: Vec<u8>,
+    ) -> Result<(StatusCode, Vec<u8>), Box<dyn std::error::Error>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, self.media_type)
+            .header(header::ACCEPT, self.media_type)
+            .body(Body::from(body))?;
+        let response = router.clone().oneshot(request).await?;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await?;
+        Ok((status, body.to_vec()))
+    }
+
+    async fn register(&mut self, router: &Router) -> Result<(), Box<dyn std::error::Error>> {
+        let body = self.announcement()?;
+        let (status, body) = self.request(router, Method::POST, "/v1/keys", body).await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let registration: Registration = serde_json::from_slice(&body)?;
+        assert_eq!(registration.peer, self.identity.peer_id().to_hex());
+        Ok(())
+    }
+
+    /// Uploads `orders`, returning the id of the batch.
+    async fn upload(
+        &mut self,
+        router: &Router,
+        orders: &Orders,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let body = self.batch(orders)?;
+        let (status, body) = self
+            .request(router, Method::POST, "/v1/batches", body)
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let upload: Upload = serde_json::from_slice(&body)?;
+        Ok(upload.batch)
+    }
+
+    /// Opens a notification channel, returning once the service confirmed it.
+    async fn subscribe(
+        &mut self,
+        addr: SocketAddr,
+        service: &PeerId,
+    ) -> Result<Subscription, Box<dyn std::error::Error>> {
+        let uri = format!("ws://{}/v1/notifications", addr);
+        let (mut socket, _) = tokio_tungstenite::connect_async(uri).await?;
+        let hello = self.envelope(Message::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            profile: self.keys.config.profile,
+            policy: MatchPolicy::default(),
+            result_modes: vec![ResultMode::FullMatrix],
+            codecs: vec![Codec::None],
+            limits: SerializationLimits::default(),
+            resume: None,
+            proofs: None,
+        }))?;
+        socket.send(self.ws_message(hello)?).await?;
+
+        let reply = self.open(&next_message(&mut socket).await?.into_data())?;
+        reply.check_signer(service)?;
+        assert!(matches!(reply.envelope.message, Message::Hello(_)));
+        Ok(socket)
+    }
+
+    fn ws_message(&self, envelope: Vec<u8>) -> Result<WsMessage, Box<dyn std::error::Error>> {
+        Ok(if self.media_type == TEXT_ENVELOPE {
+            WsMessage::text(String::from_utf8(envelope)?)
+        } else {
+            WsMessage::binary(envelope)
+        })
+    }
+
+    /// Decodes an envelope in the trader's encoding.
+    fn open(&self, body: &[u8]) -> Result<SignedEnvelope, Box<dyn std::error::Error>> {
+        let limits = SerializationLimits::default();
+        Ok(if self.media_type == TEXT_ENVELOPE {
+            Envelope::decode_text(std::str::from_utf8(body)?, &limits)?
+        } else {
+            Envelope::decode(body, &limits)?
+        })
+    }
+
+    /// Polls for the results of `batch` until matching is over.
+    async fn results(
+        &self,
+        router: &Router,
+        batch: &str,
+    ) -> Result<(StatusCode, Vec<u8>), Box<dyn std::error::Error>> {
+        let uri = format!("/v1/batches/{}/results", batch);
+        loop {
+            let (status, body) = self.request(router, Method::GET, &uri, Vec::new()).await?;
+            if status != StatusCode::ACCEPTED {
+                return Ok((status, body));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn decrypt(
+        &self,
+        body: &[u8],
+        service: &PeerId,
+    ) -> Result<Vec<bool>, Box<dyn std::error::Error>> {
+        let envelope = self.open(body)?;
+        envelope.check_signer(service)?;
+        let Message::MatchResults(results) = envelope.envelope.message else {
+            panic!("expected match results");
+        };
+        Ok(results
+            .results(&self.keys.batch_header())?
+            .iter()
+            .map(|result| result.decrypt(&self.keys.client_key))
+            .collect())
+    }
+}
+
+/// The `test_match` flow over HTTP: a trader registers its keys, uploads its
+/// orders, has them matched against the service's book and decrypts the results.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_match_over_http() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, book) = create_order_test_data(5, true);
+    let crs = generate_crs(&DarkpoolConfig::default())?;
+    let service = Service::new(Identity::generate(), book, crs.clone());
+    let service_peer = service.peer_id();
+    let router = service.router();
+
+    for media_type in [BINARY_ENVELOPE, TEXT_ENVELOPE] {
+        let mut trader = Trader::new(media_type, &crs)?;
+        trader.register(&router).await?;
+        let batch = trader.upload(&router, &orders).await?;
+
+        let uri = format!("/v1/batches/{}/match", batch);
+        let (status, _) = trader
+            .request(&router, Method::POST, &uri, Vec::new())
+            .await?;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let (status, body) = trader.results(&router, &batch).await?;
+        assert_eq!(status, StatusCode::OK);
+        let matches = trader.decrypt(&body, &service_peer)?;
+        assert_eq!(matches.len(), 25);
+        assert!(matches.iter().any(|&m| m), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+/// Subscribed traders get results pushed as soon as their resting batches have
+/// been matched against new flow, in the encoding they subscribed with.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_results_are_pushed_on_new_flow() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, flow) = create_order_test_data(3, true);
+    let crs = generate_crs(&DarkpoolConfig::default())?;
+    let service = Service::new(
+        Identity::generate(),
+        Orders { order: Vec::new() },
+        crs.clone(),
+    );
+    let service_peer = service.peer_id();
+    let router = service.router();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = service.clone();
+    tokio::spawn(async move { server.serve(listener).await });
+
+    let mut subscriptions = Vec::new();
+    for media_type in [BINARY_ENVELOPE, TEXT_ENVELOPE] {
+        let mut trader = Trader::new(media_type, &crs)?;
+        trader.register(&router).await?;
+        let batch = trader.upload(&router, &orders).await?;
+        let socket = trader.subscribe(addr, &service_peer).await?;
+        subscriptions.push((trader, batch, socket));
+    }
+
+    let failures = service.add_flow(flow).await;
+    assert!(failures.is_empty(), "unexpected failures: {:?}", failures);
+    for (trader, batch, mut socket) in subscriptions {
+        let (pushed_batch, envelope) = match next_message(&mut socket).await? {
+            WsMessage::Text(message) => {
+                let notification: Notification = serde_json::from_str(message.as_str())?;
+                let envelope = serde_json::to_vec(&notification.envelope)?;
+                (notification.batch, envelope)
+            }
+            WsMessage::Binary(message) => {
+                let (id, envelope) = message.split_at(8);
+                let id = u64::from_le_bytes(id.try_into()?);
+                (format!("{:016x}", id), envelope.to_vec())
+            }
+            message => panic!("expected a notification, got {:?}", message),
+        };
+        assert_eq!(pushed_batch, batch);
+        let matches = trader.decrypt(&envelope, &service_peer)?;
+        assert_eq!(matches.len(), 9);
+        assert!(matches.iter().any(|&m| m), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invalid_requests_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, book) = create_order_test_data(2, true);
+    let crs = generate_crs(&DarkpoolConfig::default())?;
+    let router = Service::new(Identity::generate(), book, crs.clone()).router();
+    let mut trader = Trader::new(TEXT_ENVELOPE, &crs)?;
+
+    // Orders from a trader the service has no keys for.
+    let body = trader.batch(&orders)?;
+    let (status, _) = trader
+        .request(&router, Method::POST, "/v1/batches", body)
+        .await?;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let announcement = trader.announcement()?;
+    let (status, _) = trader
+        .request(&router, Method::POST, "/v1/keys", announcement.clone())
+        .await?;
+    assert_eq!(status, StatusCode::CREATED);
+    // The same envelope again is a replay.
+    let (status, _) = trader
+        .request(&router, Method::POST, "/v1/keys", announcement)
+        .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // A key announcement is not an order batch.
+    let body = trader.announcement()?;
+    let (status, _) = trader
+        .request(&router, Method::POST, "/v1/batches", body)
+        .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Nor is an order batch without proofs.
+    let body = trader.unproven_batch(&orders)?;
+    let (status, _) = trader
+        .request(&router, Method::POST, "/v1/batches", body)
+        .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let body = trader.batch(&orders)?;
+    let (_, body) = trader
+        .request(&router, Method::POST, "/v1/batches", body)
+        .await?;
+    let upload: Upload = serde_json::from_slice(&body)?;
+    let (status, _) = trader.results(&router, &upload.batch).await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = trader.results(&router, "not-a-batch").await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::io;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+
+use fhe_darkpool_poc::config::{DarkpoolConfig, ParameterProfile};
+use fhe_darkpool_poc::error::DarkpoolError;
+use fhe_darkpool_poc::identity::Identity;
+use fhe_darkpool_poc::limits::SerializationLimits;
+use fhe_darkpool_poc::matching::{MatchPolicy, ResultMode};
+use fhe_darkpool_poc::proofs::{OrderFieldBounds, generate_crs};
+use fhe_darkpool_poc::protocol::PROTOCOL_VERSION;
+use fhe_darkpool_poc::protocol::codec::Codec;
+use fhe_darkpool_poc::session::handshake::{Agreement, Handshake, Hello};
+use fhe_darkpool_poc::session::resume::{PhaseTimeouts, RetryPolicy};
+use fhe_darkpool_poc::session::{MatchSession, Matches, Role};
+use fhe_darkpool_poc::test_data::create_order_test_data;
+use fhe_darkpool_poc::transport::MemoryTransport;
+
+/// The `test_match` flow between two parties connected over loopback TCP.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_match_over_tcp() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_one, orders_two) = create_order_test_data(5, true);
+    let (identity_one, identity_two) = (Identity::generate(), Identity::generate());
+    let (peer_one, peer_two) = (identity_one.peer_id(), identity_two.peer_id());
+    let mut one = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        identity_one,
+        orders_one,
+    );
+    let mut two = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        identity_two,
+        orders_two,
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (outcome_one, outcome_two) = tokio::join!(one.connect(addr), two.accept(&listener));
+    let (outcome_one, outcome_two) = (outcome_one?, outcome_two?);
+
+    assert_eq!(outcome_one.session_id, outcome_two.session_id);
+    assert_eq!(outcome_one.peer, peer_two);
+    assert_eq!(outcome_two.peer, peer_one);
+    for outcome in [&outcome_one, &outcome_two] {
+        // Both ends offer every result mode, so the initiator's favourite wins.
+        assert_eq!(outcome.agreement.result_mode, ResultMode::FullMatrix);
+        let Matches::Matrix(matches) = &outcome.matches else {
+            panic!("expected the full matrix, got {:?}", outcome.matches);
+        };
+        assert_eq!(matches.len(), 25);
+        assert!(outcome.matches.any(), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+/// The `test_match` flow over an in-memory transport: both parties run on the
+/// test's own thread, so the protocol steps interleave the same way every run.
+#[tokio::test]
+async fn test_match_in_memory() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_one, orders_two) = create_order_test_data(5, true);
+    let mut one = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_one,
+    );
+    let mut two = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_two,
+    );
+
+    let (end_one, end_two) = MemoryTransport::pair();
+    let (outcome_one, outcome_two) = tokio::join!(
+        one.run(end_one, Role::Initiator),
+        two.run(end_two, Role::Responder)
+    );
+    let (outcome_one, outcome_two) = (outcome_one?, outcome_two?);
+
+    assert_eq!(outcome_one.session_id, outcome_two.session_id);
+    for outcome in [&outcome_one, &outcome_two] {
+        assert_eq!(outcome.agreement.result_mode, ResultMode::FullMatrix);
+        let Matches::Matrix(matches) = &outcome.matches else {
+            panic!("expected the full matrix, got {:?}", outcome.matches);
+        };
+        assert_eq!(matches.len(), 25);
+        assert!(outcome.matches.any(), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+/// With proofs agreed on, both parties prove their batches and verify the
+/// peer's before evaluating them.
+#[tokio::test]
+async fn test_match_with_proofs() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_one, orders_two) = create_order_test_data(2, true);
+    let config = DarkpoolConfig::default();
+    let crs = generate_crs(&config)?;
+    let bounds = OrderFieldBounds::default();
+    let mut one = MatchSession::new(config.generate_keys()?, Identity::generate(), orders_one)
+        .with_proofs(crs.clone(), bounds);
+    let mut two = MatchSession::new(config.generate_keys()?, Identity::generate(), orders_two)
+        .with_proofs(crs, bounds);
+
+    let (end_one, end_two) = MemoryTransport::pair();
+    let (outcome_one, outcome_two) = tokio::join!(
+        one.run(end_one, Role::Initiator),
+        two.run(end_two, Role::Responder)
+    );
+    for outcome in [outcome_one?, outcome_two?] {
+        assert_eq!(outcome.agreement.proofs, Some(bounds));
+        assert!(outcome.matches.any(), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+/// A peer dropping its end of an in-memory connection is a transport failure,
+/// as a closed socket would be.
+#[tokio::test]
+async fn test_dropped_memory_transport_is_retryable() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders, _) = create_order_test_data(2, true);
+    let mut session = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders,
+    );
+
+    let (end, peer_end) = MemoryTransport::pair();
+    drop(peer_end);
+    match session.run(end, Role::Initiator).await {
+        Err(e) => assert!(e.is_retryable(), "expected a transport failure, got {}", e),
+        Ok(_) => panic!("expected a transport failure, got results"),
+    }
+    Ok(())
+}
+
+/// A responder only accepting match indices gets them, even from an initiator
+/// preferring the full matrix.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_negotiated_result_mode_over_tcp() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_one, orders_two) = create_order_test_data(5, true);
+    let mut one = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_one,
+    );
+    let mut two = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_two,
+    )
+    .with_result_modes(&[ResultMode::Index, ResultMode::SingleBit])
+    .with_codec(Codec::None);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (outcome_one, outcome_two) = tokio::join!(one.connect(addr), two.accept(&listener));
+    let (outcome_one, outcome_two) = (outcome_one?, outcome_two?);
+
+    for outcome in [&outcome_one, &outcome_two] {
+        assert_eq!(outcome.agreement.result_mode, ResultMode::Index);
+        assert_eq!(outcome.agreement.codec, Codec::None);
+        let Matches::FirstMatch(indices) = &outcome.matches else {
+            panic!("expected match indices, got {:?}", outcome.matches);
+        };
+        assert_eq!(indices.len(), 5);
+        assert!(indices.iter().flatten().all(|&j| j < 5));
+        assert!(outcome.matches.any(), "Expected a match, found none");
+    }
+    Ok(())
+}
+
+/// An initiator giving up while waiting for results reconnects and picks up the
+/// results its peer already computed, without either party evaluating again.
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_resumes_after_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let (orders_one, orders_two) = create_order_test_data(5, true);
+    let impatient = PhaseTimeouts {
+        evaluation: Duration::ZERO,
+        ..PhaseTimeouts::default()
+    };
+    let mut one = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_one,
+    )
+    .with_timeouts(impatient);
+    let mut two = MatchSession::new(
+        DarkpoolConfig::default().generate_keys()?,
+        Identity::generate(),
+        orders_two,
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (outcome_one, outcome_two) = tokio::join!(one.connect(addr), two.accept(&listener));
+    match outcome_one {
+        Err(DarkpoolError::Transport(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        Err(e) => panic!("expected a timeout, got {}", e),
+        Ok(_) => panic!("expected a timeout, got results"),
+    }
+    // Its peer hung up before sending results.
+    assert!(outcome_two.is_err_and(|e| e.is_retryable()));
+    let session_id = one.session_id().expect("the handshake succeeded");
+    assert_eq!(two.session_id(), Some(session_id));
+    // Both parties received the other's batch, and started evaluating it.
+    assert_eq!((one.evaluations(), two.evaluations()), (1, 1));
+
+    one.set_timeouts(PhaseTimeouts::default());
+    let retry = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_millis(10),
+    };
+    let (outcome_one, outcome_two) =
+        tokio::join!(one.connect_with_retry(addr, retry), two.accept(&listener));
+    let (outcome_one, outcome_two) = (outcome_one?, outcome_two?);
+    for outcome in [&outcome_one, &outcome_two] {
+        assert_eq!(outcome.session_id, session_id);
+        assert!(outcome.matches.any(), "Expected a match, found none");
+    }
+    // The evaluation that outlived the first connection delivered the results.
+    assert_eq!((one.evaluations(), two.evaluations()), (1, 1));
+    Ok(())
+}
+
+fn hello() -> Hello {
+    Hello {
+        version: PROTOCOL_VERSION,
+        profile: ParameterProfile::Default,
+        policy: MatchPolicy::PriceAndSide,
+        result_modes: ResultMode::ALL.to_vec(),
+        codecs: Codec::SUPPORTED.to_vec(),
+        limits: SerializationLimits::default(),
+        resume: None,
+        proofs: None,
+    }
+}
+
+/// Runs both ends of a handshake, returning what each end agreed on.
+fn handshake(
+    initiator: Hello,
+    responder: Hello,
+) -> (
+    Result<Agreement, DarkpoolError>,
+    Result<Agreement, DarkpoolError>,
+) {
+    let mut one = Handshake::new(initiator, Role::Initiator);
+    let mut two = Handshake::new(responder, Role::Responder);
+    let hello_one = one.hello().unwrap();
+    two.receive(hello_one).unwrap();
+    one.receive(two.hello().unwrap()).unwrap();
+    (one.agree(), two.agree())
+}
+
+#[test]
+fn test_handshake_agrees_on_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let tight = SerializationLimits {
+        order_batch: 1 << 20,
+        ..SerializationLimits::default()
+    };
+    let responder = Hello {
+        result_modes: vec![ResultMode::SingleBit, ResultMode::FullMatrix],
+        codecs: vec![Codec::None],
+        limits: tight,
+        ..hello()
+    };
+    let (one, two) = handshake(hello(), responder);
+    let (one, two) = (one?, two?);
+    assert_eq!(one, two);
+    assert_eq!(one.result_mode, ResultMode::FullMatrix);
+    assert_eq!(one.codec, Codec::None);
+    assert_eq!(one.limits, tight);
+    Ok(())
+}
+
+#[test]
+fn test_handshake_mismatches_fail_on_both_ends() {
+    let cases = [
+        Hello {
+            profile: ParameterProfile::LowFailureProbability,
+            ..hello()
+        },
+        Hello {
+            policy: MatchPolicy::AssetsPriceAndSide,
+            ..hello()
+        },
+        Hello {
+            result_modes: vec![],
+            ..hello()
+        },
+        Hello {
+            proofs: Some(OrderFieldBounds::default()),
+            ..hello()
+        },
+    ];
+    for responder in cases {
+        let (one, two) = handshake(hello(), responder);
+        for result in [one, two] {
+            assert!(matches!(
+                result,
+                Err(DarkpoolError::HandshakeMismatch { .. })
+            ));
+        }
+    }
+
+    let (one, _) = handshake(
+        hello(),
+        Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..hello()
+        },
+    );
+    assert!(matches!(one, Err(DarkpoolError::VersionMismatch { .. })));
+}
+
+#[test]
+fn test_handshake_steps_out_of_order_are_rejected() {
+    let mut handshake = Handshake::new(hello(), Role::Initiator);
+    assert!(handshake.agree().is_err());
+
+    let mut handshake = Handshake::new(hello(), Role::Initiator);
+    handshake.hello().unwrap();
+    assert!(handshake.hello().is_err());
+
+    let mut handshake = Handshake::new(hello(), Role::Responder);
+    handshake.receive(hello()).unwrap();
+    assert!(handshake.receive(hello()).is_err());
+    assert!(handshake.agreement().is_none());
+}